@@ -0,0 +1,221 @@
+// Simulates a circuit in a level without opening a window.
+//
+// Usage: flow-sim [options] <circuit>
+//
+// Options:
+//     --chips <dir>     chip library directory (default: chips)
+//     --level <file>    level file (default: levels/reverse.level)
+//     --ticks <n>       maximal number of ticks (default: 1000)
+//     --mode <mode>     simulation mode, `instant` or `transport` (default:
+//                       the mode of the level)
+//     --settle <n>      let switches settle within a tick, with at most n
+//                       evaluations (default: the setting of the level)
+//     --trace           print the input and output cells, the amount of
+//                       fluid in transport mode, and any diagnostics,
+//                       after every tick
+//     --flatten <file>  save the circuit with the contents of its chips laid
+//                       out in place of the chips to file, which can be
+//                       opened and edited like any other circuit
+//
+// The exit status is 0 if the level was solved, 1 if it failed, and 2 if
+// the tick limit was reached, the files could not be loaded, or the circuit
+// does not have the input and output that the level needs.
+
+extern crate flow;
+
+use std::env;
+use std::process;
+
+use flow::circuit::{ChipDb, Circuit, Hierarchy};
+use flow::flow::SimulationMode;
+use flow::level::{Level, LevelState, Outcome};
+
+struct Args {
+    circuit_path: String,
+    chip_dir: String,
+    level_path: String,
+    max_ticks: usize,
+    mode: Option<SimulationMode>,
+    settle_iterations: Option<usize>,
+    trace: bool,
+    flatten_path: Option<String>,
+}
+
+const USAGE: &'static str = "usage: flow-sim [--chips <dir>] \
+                             [--level <file>] [--ticks <n>] \
+                             [--mode <mode>] [--settle <n>] [--trace] \
+                             [--flatten <file>] <circuit>";
+
+fn parse_args() -> Result<Args, String> {
+    let mut circuit_path = None;
+    let mut chip_dir = "chips".to_string();
+    let mut level_path = "levels/reverse.level".to_string();
+    let mut max_ticks = 1000;
+    let mut mode = None;
+    let mut settle_iterations = None;
+    let mut trace = false;
+    let mut flatten_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("missing value for {}", name))
+        };
+
+        match arg.as_str() {
+            "--chips" => chip_dir = value("--chips")?,
+            "--level" => level_path = value("--level")?,
+            "--ticks" => {
+                let ticks = value("--ticks")?;
+                max_ticks = ticks.parse().map_err(
+                    |_| format!("invalid number of ticks: {}", ticks),
+                )?;
+            }
+            "--mode" => {
+                let name = value("--mode")?;
+                mode = Some(match name.as_str() {
+                    "instant" => SimulationMode::Instant,
+                    "transport" => SimulationMode::Transport,
+                    _ => return Err(format!("invalid mode: {}", name)),
+                });
+            }
+            "--settle" => {
+                let iterations = value("--settle")?;
+                settle_iterations = match iterations.parse() {
+                    Ok(n) if n >= 2 => Some(n),
+                    _ => {
+                        return Err(format!(
+                            "invalid number of settle iterations: {}",
+                            iterations
+                        ))
+                    }
+                };
+            }
+            "--trace" => trace = true,
+            "--flatten" => flatten_path = Some(value("--flatten")?),
+            _ if arg.starts_with("--") => {
+                return Err(format!("unknown option: {}", arg))
+            }
+            _ if circuit_path.is_none() => circuit_path = Some(arg),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    let circuit_path =
+        circuit_path.ok_or_else(|| "missing circuit".to_string())?;
+
+    Ok(Args {
+        circuit_path,
+        chip_dir,
+        level_path,
+        max_ticks,
+        mode,
+        settle_iterations,
+        trace,
+        flatten_path,
+    })
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("flow-sim: {}", msg);
+    process::exit(2);
+}
+
+// Cells and components in diagnostics are those of the circuit, see
+// flow::Diagnostic::with_origins
+fn print_trace(tick: usize, level_state: &LevelState, hierarchy: &Hierarchy) {
+    let flow = &level_state.flow;
+    let inputs = flow.input_cells
+        .iter()
+        .map(|&i| if flow.flow.node(i).enabled { "1" } else { "0" })
+        .collect::<Vec<_>>();
+    let outputs = flow.output_cells
+        .iter()
+        .map(|&i| format!("{:.3}", flow.flow.node(i).in_flow))
+        .collect::<Vec<_>>();
+
+    println!(
+        "tick {}: inputs [{}] outputs [{}]",
+        tick,
+        inputs.join(" "),
+        outputs.join(" ")
+    );
+
+    if flow.mode == SimulationMode::Transport {
+        let stored = (0..flow.graph.num_nodes())
+            .map(|i| flow.flow.node(i).load + flow.flow.node(i).fill)
+            .sum::<f64>();
+        println!(
+            "    fluid: supplied {:.3} absorbed {:.3} stored {:.3}",
+            flow.supplied,
+            flow.absorbed,
+            stored
+        );
+    }
+
+    for diagnostic in flow.diagnostics.iter() {
+        println!("    {}", diagnostic.with_origins(hierarchy));
+    }
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|msg| {
+        eprintln!("flow-sim: {}", msg);
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    });
+
+    let chip_db = ChipDb::load(&args.chip_dir).unwrap_or_else(|err| {
+        fail(format!("can't load chips from {}: {}", args.chip_dir, err))
+    });
+    let circuit = Circuit::load_file(&args.circuit_path).unwrap_or_else(|err| {
+        fail(format!("can't load {}: {}", args.circuit_path, err))
+    });
+    let mut level = Level::load_file(&args.level_path).unwrap_or_else(|err| {
+        fail(format!("can't load {}: {}", args.level_path, err))
+    });
+
+    if let Some(mode) = args.mode {
+        level.mode = mode;
+    }
+    if args.settle_iterations.is_some() {
+        level.settle_iterations = args.settle_iterations;
+    }
+
+    if let Err(err) = chip_db.check_circuit(&circuit) {
+        fail(format!("invalid circuit: {}", err));
+    }
+
+    if let Some(ref flatten_path) = args.flatten_path {
+        let (flattened_circuit, _hierarchy) =
+            circuit.flatten(&chip_db).unwrap_or_else(|err| {
+                fail(format!("can't flatten circuit: {}", err))
+            });
+        if let Err(err) = flattened_circuit.save_file(flatten_path) {
+            fail(format!("can't save {}: {}", flatten_path, err));
+        }
+    }
+
+    let (mut level_state, hierarchy) = level
+        .unfold_state(&circuit, &chip_db)
+        .unwrap_or_else(|err| fail(format!("can't start level: {}", err)));
+
+    for tick in 0..args.max_ticks {
+        let outcome = level_state.time_step();
+
+        if args.trace {
+            print_trace(tick, &level_state, &hierarchy);
+        }
+
+        if let Some(outcome) = outcome {
+            println!("{:?} after {} ticks", outcome, tick + 1);
+            process::exit(match outcome {
+                Outcome::Success => 0,
+                Outcome::Failure => 1,
+            });
+        }
+    }
+
+    println!("No outcome after {} ticks", args.max_ticks);
+    process::exit(2);
+}
@@ -1,8 +1,11 @@
 use std::collections::{HashSet, HashMap};
+use std::mem;
 
 use types::Dir;
+use kinds;
 
-use super::{Coords, CellId, Element, ComponentId, Component, Edge, Circuit};
+use super::{Coords, CellId, ChipId, Element, ComponentId, Component, Edge,
+            Circuit};
 
 #[derive(Clone)]
 pub enum Action {
@@ -15,6 +18,11 @@ pub enum Action {
     RemoveEdge(CellId, CellId),
     PlaceCircuitAtPos(Circuit, Coords),
     RemoveComponents(HashSet<ComponentId>),
+
+    // Replace the element of a component by one with the same cells, for
+    // changing parameters of the element
+    SetElement(ComponentId, Element),
+
     ReverseCompound(Vec<Action>),
 }
 
@@ -37,11 +45,7 @@ impl Action {
                 if let Some(component_id) = point {
                     let element =
                         &circuit.components.get(&component_id).unwrap().element;
-                    match element {
-                        &Element::Input { .. } => false,
-                        &Element::Output { .. } => false,
-                        _ => true,
-                    }
+                    !kinds::get(element).is_fixed()
                 } else {
                     false
                 }
@@ -111,6 +115,11 @@ impl Action {
             &Action::RemoveComponents(ref ids) => {
                 ids.iter().all(|id| circuit.components.contains_key(id))
             }
+            &Action::SetElement(id, ref element) => {
+                circuit.components.get(&id).map_or(false, |component| {
+                    component.element.descr() == element.descr()
+                })
+            }
             &Action::ReverseCompound(_) => {
                 // ReverseCompound not included here
                 true
@@ -279,6 +288,12 @@ impl Action {
                     .collect();
                 Action::ReverseCompound(undo)
             }
+            Action::SetElement(id, element) => {
+                let component = circuit.components.get_mut(&id).unwrap();
+                let old_element =
+                    mem::replace(&mut component.element, element);
+                Action::SetElement(id, old_element)
+            }
             Action::ReverseCompound(actions) => {
                 let undo = actions
                     .into_iter()
@@ -290,6 +305,31 @@ impl Action {
         }
     }
 
+    // Replace all references to a chip by another chip id, like
+    // Circuit::rename_chip
+    pub fn rename_chip(&mut self, old_id: &ChipId, new_id: &ChipId) {
+        match self {
+            &mut Action::NoUndo(ref mut action) => {
+                action.rename_chip(old_id, new_id)
+            }
+            &mut Action::PlaceComponent(ref mut component) => {
+                component.element.rename_chip(old_id, new_id)
+            }
+            &mut Action::PlaceCircuitAtPos(ref mut circuit, _) => {
+                circuit.rename_chip(old_id, new_id)
+            }
+            &mut Action::SetElement(_, ref mut element) => {
+                element.rename_chip(old_id, new_id)
+            }
+            &mut Action::ReverseCompound(ref mut actions) => {
+                for action in actions.iter_mut() {
+                    action.rename_chip(old_id, new_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub fn try_perform(self, circuit: &mut Circuit) -> Option<Action> {
         if self.can_perform(circuit) {
             Some(self.perform(circuit))
@@ -1,8 +1,21 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use cgmath::Zero;
 
-use super::{Coords, ComponentId, ChipId, ChipDescr, Element, Circuit, Action};
+use format::{self, Reader};
+
+use super::{Coords, ComponentId, ChipId, ChipDescr, Element, Circuit, Action,
+            UnfoldError, DEFAULT_PRESSURE};
+use super::template::Template;
+
+// File extension of chips in the library directory. The file name without
+// the extension is the ChipId.
+pub const CHIP_EXTENSION: &'static str = "chip";
 
 pub struct Chip {
     pub descr: ChipDescr,
@@ -11,24 +24,64 @@ pub struct Chip {
     pub right_input_id: ComponentId,
 }
 
+// A library of chips that is stored in a directory, with one file per chip.
+// A chip file looks like this:
+//
+//     flow chip <version>
+//     descr <inner_width> <inner_height> <left_size> <right_size>
+//     inputs <left_input_id> <right_input_id>
+//     circuit
+//     <circuit, as described in circuit::file>
+//
+// Chips share the version with circuits, see format::VERSION. The version
+// is passed on for reading the circuit.
+//
+// Chips are kept sorted by their id, so that iteration order is stable.
 pub struct ChipDb {
-    chips: HashMap<ChipId, Chip>,
+    dir: PathBuf,
+    chips: BTreeMap<ChipId, Chip>,
+
+    // Ids of chips that have been renamed since the last save. Their files
+    // are removed when saving.
+    removed: BTreeSet<ChipId>,
+
+    // Unfolded chips, which are made when they are first needed, see
+    // Circuit::unfold_into. Taking a chip's circuit for editing removes the
+    // templates that depend on it.
+    templates: RefCell<HashMap<ChipId, Rc<Template>>>,
+
+    // The chips that occur as components in the circuit of each chip. Like
+    // the templates, they are found when they are first needed, and taking
+    // the chip's circuit for editing removes them.
+    chip_deps: RefCell<HashMap<ChipId, BTreeSet<ChipId>>>,
 }
 
-impl ChipDb {
-    fn new_circuit(descr: &ChipDescr) -> (Circuit, ComponentId, ComponentId) {
+// Chip ids are used as file names, and as tokens in circuit files
+pub fn is_valid_chip_id(id: &str) -> bool {
+    !id.is_empty() &&
+        id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Chip {
+    pub fn new(descr: ChipDescr) -> Chip {
         let mut circuit = Circuit::new();
 
-        let left_id = {
-            let element = Element::Input { size: descr.left_size };
+        let left_input_id = {
+            let element = Element::Input {
+                size: descr.left_size,
+                pressure: DEFAULT_PRESSURE,
+            };
             let pos = Coords::zero();
             let component = element.new_component(pos, 0);
             let action = Action::PlaceComponent(component);
             action.perform(&mut circuit);
             circuit.get_last_component_id().unwrap()
         };
-        let right_id = {
-            let element = Element::Input { size: descr.right_size };
+        let right_input_id = {
+            let element = Element::Input {
+                size: descr.right_size,
+                pressure: DEFAULT_PRESSURE,
+            };
             let pos = Coords::new(descr.inner_size.x, 0);
             let component = element.new_component(pos, 0);
             let action = Action::PlaceComponent(component);
@@ -36,31 +89,267 @@ impl ChipDb {
             circuit.get_last_component_id().unwrap()
         };
 
-        (circuit, left_id, right_id)
-    }
-
-    pub fn init(n_chips: usize) -> ChipDb {
-        let chips = (2..2 + n_chips)
-            .map(|i| {
-                let descr = ChipDescr {
-                    inner_size: Coords::new(5, 5),
-                    left_size: 1 + i % 3,
-                    right_size: 1 + i % 3,
-                };
-                let (circuit, left_input_id, right_input_id) =
-                    Self::new_circuit(&descr);
-                let chip = Chip {
-                    descr,
-                    circuit,
-                    left_input_id,
-                    right_input_id,
-                };
-
-                (i, chip)
-            })
-            .collect();
+        Chip {
+            descr,
+            circuit,
+            left_input_id,
+            right_input_id,
+        }
+    }
+
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "flow chip {}", format::VERSION)?;
+        writeln!(
+            w,
+            "descr {} {} {} {}",
+            self.descr.inner_size.x,
+            self.descr.inner_size.y,
+            self.descr.left_size,
+            self.descr.right_size
+        )?;
+        writeln!(w, "inputs {} {}", self.left_input_id, self.right_input_id)?;
+        writeln!(w, "circuit")?;
+        self.circuit.write(w)
+    }
+
+    pub fn load<R: BufRead>(read: R) -> format::Result<Chip> {
+        let mut reader = Reader::new(read);
+        let version = reader.header("chip")?;
+
+        let descr = {
+            let mut line = reader.expect_line("`descr`")?;
+            if line.keyword() != "descr" {
+                return Err(line.error(format!("expected `descr`")));
+            }
+            let inner_x = line.next("chip width")?;
+            let inner_y = line.next("chip height")?;
+            let left_size = line.next("chip left size")?;
+            let right_size = line.next("chip right size")?;
+            line.finish()?;
+
+            ChipDescr {
+                inner_size: Coords::new(inner_x, inner_y),
+                left_size: left_size,
+                right_size: right_size,
+            }
+        };
+
+        let (left_input_id, right_input_id) = {
+            let mut line = reader.expect_line("`inputs`")?;
+            if line.keyword() != "inputs" {
+                return Err(line.error(format!("expected `inputs`")));
+            }
+            let left_input_id = line.next("component id")?;
+            let right_input_id = line.next("component id")?;
+            line.finish()?;
+
+            (left_input_id, right_input_id)
+        };
+
+        {
+            let line = reader.expect_line("`circuit`")?;
+            if line.keyword() != "circuit" {
+                return Err(line.error(format!("expected `circuit`")));
+            }
+            line.finish()?;
+        }
+
+        let circuit = Circuit::read(&mut reader, version)?;
+
+        if let Some(line) = reader.next_line()? {
+            return Err(line.error(
+                format!("unexpected `{}` after end", line.keyword()),
+            ));
+        }
+
+        // The inputs are the gluing points to the outer circuit, so their
+        // number of cells has to match the chip's description.
+        let check_input = |id: ComponentId, size: usize| {
+            match circuit.components().get(&id).map(|c| &c.element) {
+                Some(&Element::Input {
+                    size: input_size, ..
+                })
+                    if input_size == size => Ok(()),
+                _ => Err(format::Error::Invalid(format!(
+                    "component {} is not an input of size {}",
+                    id,
+                    size
+                ))),
+            }
+        };
+        check_input(left_input_id, descr.left_size)?;
+        check_input(right_input_id, descr.right_size)?;
+
+        Ok(Chip {
+            descr,
+            circuit,
+            left_input_id,
+            right_input_id,
+        })
+    }
+}
+
+impl ChipDb {
+    // Create an empty chip library that will be saved to the given directory
+    pub fn new<P: AsRef<Path>>(dir: P) -> ChipDb {
+        ChipDb {
+            dir: dir.as_ref().to_path_buf(),
+            chips: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            templates: RefCell::new(HashMap::new()),
+            chip_deps: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Load all chips in a library directory. A directory that does not exist
+    // yet results in an empty library.
+    pub fn load<P: AsRef<Path>>(dir: P) -> format::Result<ChipDb> {
+        let mut chip_db = ChipDb::new(&dir);
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(chip_db)
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+
+            if path.extension().map_or(true, |ext| ext != CHIP_EXTENSION) {
+                continue;
+            }
+
+            let id = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(|stem| stem.to_string());
+            let id = match id {
+                Some(ref id) if is_valid_chip_id(id) => id.clone(),
+                _ => {
+                    return Err(format::Error::Invalid(format!(
+                        "invalid chip file name: {}",
+                        path.display()
+                    )))
+                }
+            };
+
+            let chip = File::open(&path)
+                .map_err(format::Error::from)
+                .and_then(|file| Chip::load(BufReader::new(file)))
+                .map_err(|err| {
+                    format::Error::Invalid(
+                        format!("{}: {}", path.display(), err),
+                    )
+                })?;
+
+            chip_db.chips.insert(id, chip);
+        }
+
+        for (id, chip) in chip_db.chips.iter() {
+            chip_db.check_circuit(&chip.circuit).map_err(|err| {
+                format::Error::Invalid(format!("chip {}: {}", id, err))
+            })?;
+        }
+
+        Ok(chip_db)
+    }
+
+    // Write all chips to the library directory
+    pub fn save(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        for (id, chip) in self.chips.iter() {
+            let mut w = BufWriter::new(File::create(self.chip_path(id))?);
+            chip.save(&mut w)?;
+            w.flush()?;
+        }
+
+        for id in self.removed.iter() {
+            match fs::remove_file(self.chip_path(id)) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        self.removed.clear();
+
+        Ok(())
+    }
+
+    fn chip_path(&self, id: &ChipId) -> PathBuf {
+        self.dir.join(id).with_extension(CHIP_EXTENSION)
+    }
+
+    // Make sure that every chip referenced in a circuit exists, and that its
+    // description is up to date.
+    pub fn check_circuit(&self, circuit: &Circuit) -> Result<(), String> {
+        for component in circuit.components().values() {
+            if let Element::Chip(ref id, ref descr) = component.element {
+                match self.get_descr(id) {
+                    Some(chip_descr) if chip_descr == descr => {}
+                    Some(_) => {
+                        return Err(format!("chip {} has changed size", id))
+                    }
+                    None => return Err(format!("unknown chip {}", id)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, id: ChipId, descr: ChipDescr) -> Result<(), String> {
+        if !is_valid_chip_id(&id) {
+            return Err(format!("invalid chip id: `{}`", id));
+        }
+        if self.chips.contains_key(&id) {
+            return Err(format!("chip {} exists already", id));
+        }
+
+        self.removed.remove(&id);
+        self.chips.insert(id, Chip::new(descr));
+
+        Ok(())
+    }
+
+    // Rename a chip. References to the chip in all chips of the library, as
+    // well as in the given circuit, are updated.
+    pub fn rename(
+        &mut self,
+        old_id: &ChipId,
+        new_id: ChipId,
+        circuit: &mut Circuit,
+    ) -> Result<(), String> {
+        if !is_valid_chip_id(&new_id) {
+            return Err(format!("invalid chip id: `{}`", new_id));
+        }
+        if self.chips.contains_key(&new_id) {
+            return Err(format!("chip {} exists already", new_id));
+        }
+
+        let chip = match self.chips.remove(old_id) {
+            Some(chip) => chip,
+            None => return Err(format!("unknown chip {}", old_id)),
+        };
+        self.chips.insert(new_id.clone(), chip);
+
+        self.removed.remove(&new_id);
+        self.removed.insert(old_id.clone());
+
+        // All the circuits that refer to the chip change
+        self.templates.borrow_mut().clear();
+        self.chip_deps.borrow_mut().clear();
+
+        for chip in self.chips.values_mut() {
+            chip.circuit.rename_chip(old_id, &new_id);
+        }
+        circuit.rename_chip(old_id, &new_id);
+
+        Ok(())
+    }
 
-        ChipDb { chips }
+    pub fn ids(&self) -> Vec<ChipId> {
+        self.chips.keys().cloned().collect()
     }
 
     pub fn get(&self, id: &ChipId) -> Option<&Chip> {
@@ -75,7 +364,179 @@ impl ChipDb {
         self.chips.get(id).map(|chip| &chip.circuit)
     }
 
+    // The circuit may be changed through the result, so the templates and
+    // dependencies that involve the chip are found again the next time they
+    // are needed
     pub fn get_circuit_mut(&mut self, id: &ChipId) -> Option<&mut Circuit> {
+        self.templates
+            .borrow_mut()
+            .retain(|_, template| !template.depends_on(id));
+        self.chip_deps.borrow_mut().remove(id);
+
         self.chips.get_mut(id).map(|chip| &mut chip.circuit)
     }
+
+    // Unfolded circuit of a chip that is nested in the chips of `chip_path`,
+    // see Template. Fails if the chip is one of them, since the chips would
+    // contain each other.
+    pub fn template(
+        &self,
+        id: &ChipId,
+        chip_path: &mut Vec<ChipId>,
+    ) -> Result<Rc<Template>, UnfoldError> {
+        if let Some(template) = self.templates.borrow().get(id) {
+            return Ok(template.clone());
+        }
+
+        let chip = match self.get(id) {
+            Some(chip) => chip,
+            None => return Err(UnfoldError::MissingChip(id.clone())),
+        };
+
+        // The editor does not place chips that would contain each other, see
+        // can_place, but chip files can also be changed by hand
+        let cycle_start = chip_path.iter().position(|chip_id| chip_id == id);
+        if let Some(cycle_start) = cycle_start {
+            let mut cycle = chip_path.split_off(cycle_start);
+            cycle.push(id.clone());
+            return Err(UnfoldError::Cycle(cycle));
+        }
+
+        chip_path.push(id.clone());
+        let template = Template::new(id, chip, self, chip_path);
+        chip_path.pop();
+
+        let template = Rc::new(template?);
+        self.templates
+            .borrow_mut()
+            .insert(id.clone(), template.clone());
+
+        Ok(template)
+    }
+
+    // Whether a component of the given element can be placed into the
+    // circuit of chip `into_id`, where None is the main circuit. Chips must
+    // not contain themselves, neither directly nor through other chips.
+    pub fn can_place(
+        &self,
+        element: &Element,
+        into_id: &Option<ChipId>,
+    ) -> bool {
+        match (element, into_id) {
+            (&Element::Chip(ref chip_id, _), &Some(ref into_id)) => {
+                !self.contains(chip_id, into_id)
+            }
+            _ => true,
+        }
+    }
+
+    // Whether chip `id` is chip `other_id`, or contains it somewhere inside
+    pub fn contains(&self, id: &ChipId, other_id: &ChipId) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![id.clone()];
+
+        while let Some(id) = stack.pop() {
+            if id == *other_id {
+                return true;
+            }
+
+            let deps = self.chip_deps(&id);
+            visited.insert(id);
+            stack.extend(deps.into_iter().filter(|id| !visited.contains(id)));
+        }
+
+        false
+    }
+
+    // Chips that occur as components in the circuit of a chip
+    fn chip_deps(&self, id: &ChipId) -> BTreeSet<ChipId> {
+        if let Some(deps) = self.chip_deps.borrow().get(id) {
+            return deps.clone();
+        }
+
+        let deps = self.get_circuit(id)
+            .map(|circuit| {
+                circuit
+                    .components()
+                    .values()
+                    .filter_map(|component| match component.element {
+                        Element::Chip(ref chip_id, _) => Some(chip_id.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(BTreeSet::new);
+        self.chip_deps.borrow_mut().insert(id.clone(), deps.clone());
+
+        deps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place_chip(id: &str, descr: &ChipDescr, circuit: &mut Circuit) {
+        let element = Element::Chip(id.to_string(), descr.clone());
+        let component = element.new_component(Coords::new(2, 2), 0);
+        let action = Action::PlaceComponent(component);
+        assert!(action.can_perform(circuit));
+        action.perform(circuit);
+    }
+
+    // Every chip component, in the circuit and in the library, refers to an
+    // existing chip, and the circuit can still be unfolded
+    fn check_chips(chip_db: &ChipDb, circuit: &Circuit) {
+        assert!(chip_db.check_circuit(circuit).is_ok());
+        for id in chip_db.ids() {
+            let chip_circuit = chip_db.get_circuit(&id).unwrap();
+            assert!(chip_db.check_circuit(chip_circuit).is_ok());
+        }
+        assert!(circuit.unfold(chip_db).is_ok());
+    }
+
+    #[test]
+    fn rename_nested_chip() {
+        let descr = ChipDescr {
+            inner_size: Coords::new(10, 10),
+            left_size: 1,
+            right_size: 1,
+        };
+        let mut chip_db = ChipDb::new("chips");
+        chip_db.add("inner".to_string(), descr.clone()).unwrap();
+        chip_db.add("outer".to_string(), descr.clone()).unwrap();
+
+        place_chip(
+            "inner",
+            &descr,
+            chip_db.get_circuit_mut(&"outer".to_string()).unwrap(),
+        );
+
+        let mut circuit = Circuit::new();
+        place_chip("outer", &descr, &mut circuit);
+        check_chips(&chip_db, &circuit);
+
+        chip_db
+            .rename(&"inner".to_string(), "renamed".to_string(), &mut circuit)
+            .unwrap();
+        assert!(chip_db.get(&"inner".to_string()).is_none());
+        check_chips(&chip_db, &circuit);
+
+        chip_db
+            .rename(&"outer".to_string(), "top".to_string(), &mut circuit)
+            .unwrap();
+        check_chips(&chip_db, &circuit);
+
+        // The components of both chips are still there after unfolding
+        let (unfolded, _) = circuit.unfold(&chip_db).unwrap();
+        let num_chips = unfolded
+            .components()
+            .values()
+            .filter(|component| match component.element {
+                Element::Chip(..) => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(num_chips, 2);
+    }
 }
@@ -1,9 +1,8 @@
-use std::cmp;
-
 use cgmath::Zero;
 
 use types::{Dir, Rect};
 use circuit;
+use kinds;
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum SwitchType {
@@ -11,8 +10,38 @@ pub enum SwitchType {
     Off,
 }
 
-// Might be changed to a string later on
-pub type ChipId = usize;
+// How the control cells of a gate are combined. With more than two control
+// cells, Xor is enabled if an odd number of them is active.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GateType {
+    And,
+    Or,
+    Xor,
+}
+
+// Flow into a control cell at which a switch, gate or power component reacts.
+// The control cell becomes active when the flow rises above `on`, and inactive
+// again when the flow falls below `off`. Choosing `off` smaller than `on`
+// gives hysteresis, so that a noisy flow near the threshold does not make the
+// component chatter.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Threshold {
+    pub on: f64,
+    pub off: f64,
+}
+
+pub const DEFAULT_THRESHOLD: f64 = 0.01;
+
+// Pressure of sources, inputs and power components, unless given otherwise
+pub const DEFAULT_PRESSURE: f64 = 100.0;
+
+// Amount of fluid that a reservoir can hold, unless given otherwise. A full
+// reservoir has DEFAULT_PRESSURE.
+pub const DEFAULT_CAPACITY: f64 = 100.0;
+
+// Name of a chip in the ChipDb. Only ASCII letters, digits, `_` and `-` are
+// allowed, see chip_db::is_valid_chip_id.
+pub type ChipId = String;
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct ChipDescr {
@@ -21,16 +50,46 @@ pub struct ChipDescr {
     pub right_size: usize,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum Element {
     Node,
     Bridge,
-    Switch(SwitchType),
-    Source,
+    Switch(SwitchType, Threshold),
+
+    // A switch with `inputs` control cells (at least two). Each control cell
+    // is active according to the threshold, and the flow cell is enabled if
+    // the control cells are active in the combination given by the GateType.
+    Gate {
+        kind: GateType,
+        inputs: usize,
+        threshold: Threshold,
+    },
+
+    Source { pressure: f64 },
     Sink,
-    Input { size: usize },
+    Input { size: usize, pressure: f64 },
     Output { size: usize },
-    Power,
+    Power { threshold: Threshold, pressure: f64 },
+
+    // One-way valve. Fluid can only pass from the inlet (cell 0, on the left)
+    // to the outlet (cell 1, on the right).
+    Valve,
+
+    // Connects its two cells while it is on. Clicking it during the
+    // simulation turns it on or off, `on` is only how it starts.
+    Toggle { on: bool },
+
+    // Stores fluid across ticks. The pressure of a reservoir is proportional
+    // to how full it is, so it fills up from higher pressure and drains into
+    // lower pressure.
+    Reservoir { capacity: f64 },
+
+    // Sources that are switched on and off depending on the simulation tick.
+    // A clock is on for the first `duty` ticks of every `period` ticks. A
+    // pattern is on in tick `t` if `bits[t % bits.len()]` is set.
+    Clock { period: usize, duty: usize },
+    Pattern { bits: Vec<bool> },
+
     Chip(ChipId, ChipDescr),
 }
 
@@ -48,7 +107,7 @@ pub struct ElementDescr {
     pub cell_edges: Vec<Vec<Dir>>,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub struct Component {
     pub element: Element,
 
@@ -67,111 +126,71 @@ pub struct Component {
     pub cell_edges: Vec<Vec<Dir>>,
 }
 
-impl Element {
-    pub fn descr(&self) -> ElementDescr {
-        let (size, cells, cell_edges) = match self {
-            &Element::Node => (
-                circuit::Coords::new(0, 0),
-                vec![(Dir::Left, 0)],
-                None,
-            ),
-            &Element::Bridge => (
-                circuit::Coords::new(0, 0),
-                vec![(Dir::Left, 0), (Dir::Left, 0)],
-                Some(vec![
-                    vec![Dir::Up, Dir::Down],
-                    vec![Dir::Left, Dir::Right],
-                ]),
-            ),
-            &Element::Switch(_) => (
-                circuit::Coords::new(0, 0),
-                vec![(Dir::Left, 0), (Dir::Right, 0)],
-                Some(vec![
-                    vec![Dir::Left],
-                    vec![Dir::Right, Dir::Up, Dir::Down],
-                ]),
-            ),
-            &Element::Source => (
-                circuit::Coords::new(0, 0),
-                vec![(Dir::Right, 0)],
-                None,
-            ),
-            &Element::Sink => (
-                circuit::Coords::new(0, 0),
-                vec![(Dir::Left, 0)],
-                None,
-            ),
-            &Element::Input { ref size } => (
-                circuit::Coords::new(
-                    0,
-                    *size as isize - 1,
-                ),
-                (0..*size)
-                    .map(|i| (Dir::Left, i))
-                    .collect(),
-                None,
-            ),
-            &Element::Output { ref size } => (
-                circuit::Coords::new(
-                    0,
-                    *size as isize - 1,
-                ),
-                (0..*size)
-                    .map(|i| (Dir::Left, i))
-                    .collect(),
-                None,
-            ),
-            &Element::Power => (
-                circuit::Coords::new(0, 0),
-                vec![(Dir::Left, 0), (Dir::Left, 0)],
-                Some(vec![vec![Dir::Left], vec![Dir::Right]]),
-            ),
-            &Element::Chip(ref _id, ref descr) => {
-                let height = cmp::max(descr.left_size, descr.right_size);
-                let left_cells = (0..descr.left_size).map(|i| (Dir::Left, i));
-                let right_cells =
-                    (0..descr.right_size).map(|i| (Dir::Right, i)).rev();
-                let cells = left_cells.chain(right_cells).collect();
-                let left_edges = (0..descr.left_size).map(|_| vec![Dir::Left]);
-                let right_edges =
-                    (0..descr.right_size).map(|_| vec![Dir::Right]);
-                let edges = left_edges.chain(right_edges).collect();
-                (
-                    circuit::Coords::new(1, height as isize - 1),
-                    cells,
-                    Some(edges),
-                )
-            }
-        };
+impl Threshold {
+    // A threshold without hysteresis
+    pub fn new(level: f64) -> Threshold {
+        Threshold {
+            on: level,
+            off: level,
+        }
+    }
 
-        let cell_edges = match cell_edges {
-            Some(cell_edges) => cell_edges,
-            None => {
-                let rect = Rect {
-                    pos: circuit::Coords::zero(),
-                    size: size,
-                };
+    pub fn is_valid(&self) -> bool {
+        0.0 <= self.off && self.off <= self.on && self.on.is_finite()
+    }
 
-                cells
-                    .iter()
-                    .map(|&(cell_dir, cell_k)| {
-                        let corner = rect.first_corner_cw(cell_dir);
-                        let perp_dir = cell_dir.rotate_cw();
-                        let cell_pos = perp_dir.apply_n(corner, cell_k);
-                        Dir::iter()
-                            .filter_map(|&edge_dir| if rect.is_within(
-                                edge_dir.apply(cell_pos),
-                            )
-                            {
-                                None
-                            } else {
-                                Some(edge_dir)
-                            })
-                            .collect()
+    // Whether the component is active after seeing the given flow, if it was
+    // active before or not
+    pub fn apply(&self, active: bool, flow: f64) -> bool {
+        if active {
+            flow >= self.off
+        } else {
+            flow > self.on
+        }
+    }
+}
+
+impl Default for Threshold {
+    fn default() -> Threshold {
+        Threshold::new(DEFAULT_THRESHOLD)
+    }
+}
+
+// The bits of a pattern as a sequence of `0` and `1`
+pub fn pattern_to_string(bits: &[bool]) -> String {
+    bits.iter().map(|&bit| if bit { '1' } else { '0' }).collect()
+}
+
+impl ElementDescr {
+    // Describe an element whose cells have edges in all directions that lead
+    // out of its rect
+    pub fn with_outer_edges(
+        size: circuit::Coords,
+        cells: Vec<(Dir, usize)>,
+    ) -> ElementDescr {
+        let rect = Rect {
+            pos: circuit::Coords::zero(),
+            size: size,
+        };
+
+        let cell_edges = cells
+            .iter()
+            .map(|&(cell_dir, cell_k)| {
+                let corner = rect.first_corner_cw(cell_dir);
+                let perp_dir = cell_dir.rotate_cw();
+                let cell_pos = perp_dir.apply_n(corner, cell_k);
+                Dir::iter()
+                    .filter_map(|&edge_dir| if rect.is_within(
+                        edge_dir.apply(cell_pos),
+                    )
+                    {
+                        None
+                    } else {
+                        Some(edge_dir)
                     })
                     .collect()
-            }
-        };
+            })
+            .collect();
 
         ElementDescr {
             size,
@@ -179,6 +198,21 @@ impl Element {
             cell_edges,
         }
     }
+}
+
+impl Element {
+    pub fn descr(&self) -> ElementDescr {
+        kinds::get(self).descr(self)
+    }
+
+    // Replace a reference to a chip by another chip id
+    pub fn rename_chip(&mut self, old_id: &ChipId, new_id: &ChipId) {
+        if let &mut Element::Chip(ref mut id, _) = self {
+            if id == old_id {
+                *id = new_id.clone();
+            }
+        }
+    }
 
     pub fn new_component(
         &self,
@@ -0,0 +1,650 @@
+// Reading and writing circuits in the text format described in `format`.
+//
+// A circuit is stored as follows:
+//
+//     flow circuit <version>
+//     next_component_id <id>
+//     component <id> <x> <y> <rotation_cw> <element>
+//     ...
+//     edge <component_id_a> <cell_a> <component_id_b> <cell_b> <resistance>
+//     ...
+//     end
+//
+// where <element> is one of
+//
+//     node
+//     bridge
+//     switch on|off <threshold_on> <threshold_off>
+//     gate and|or|xor <inputs> <threshold_on> <threshold_off>
+//     source <pressure>
+//     sink
+//     input <size> <pressure>
+//     output <size>
+//     power <threshold_on> <threshold_off> <pressure>
+//     valve
+//     toggle on|off
+//     reservoir <capacity>
+//     clock <period> <duty>
+//     pattern <bits>
+//     chip <chip_id> <inner_width> <inner_height> <left_size> <right_size>
+//
+// with <version> being format::VERSION. Thresholds are described in
+// circuit::Threshold. The bits of a pattern are written as a sequence of `0`
+// and `1`, for example `0110`.
+//
+// Components and edges are written in ascending order of their ids, so that
+// saving the same circuit twice gives the same file. Only the components and
+// edges are stored, everything else is derived from them when loading.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use types::Dir;
+use format::{self, Reader, Line};
+
+use super::{Coords, ComponentId, ChipId, ChipDescr, SwitchType, GateType,
+            Threshold,
+            Element, Edge, Circuit, DEFAULT_RESISTANCE, DEFAULT_PRESSURE,
+            is_valid_chip_id, pattern_to_string};
+
+fn write_element<W: Write>(w: &mut W, element: &Element) -> io::Result<()> {
+    match element {
+        &Element::Node => write!(w, "node"),
+        &Element::Bridge => write!(w, "bridge"),
+        &Element::Switch(kind, threshold) => {
+            let kind = match kind {
+                SwitchType::On => "on",
+                SwitchType::Off => "off",
+            };
+            write!(w, "switch {} {} {}", kind, threshold.on, threshold.off)
+        }
+        &Element::Gate {
+            kind,
+            inputs,
+            threshold,
+        } => {
+            let kind = match kind {
+                GateType::And => "and",
+                GateType::Or => "or",
+                GateType::Xor => "xor",
+            };
+            write!(
+                w,
+                "gate {} {} {} {}",
+                kind,
+                inputs,
+                threshold.on,
+                threshold.off
+            )
+        }
+        &Element::Source { pressure } => write!(w, "source {}", pressure),
+        &Element::Sink => write!(w, "sink"),
+        &Element::Input { size, pressure } => {
+            write!(w, "input {} {}", size, pressure)
+        }
+        &Element::Output { size } => write!(w, "output {}", size),
+        &Element::Power {
+            threshold,
+            pressure,
+        } => write!(
+            w,
+            "power {} {} {}",
+            threshold.on,
+            threshold.off,
+            pressure
+        ),
+        &Element::Valve => write!(w, "valve"),
+        &Element::Toggle { on } => {
+            write!(w, "toggle {}", if on { "on" } else { "off" })
+        }
+        &Element::Reservoir { capacity } => {
+            write!(w, "reservoir {}", capacity)
+        }
+        &Element::Clock { period, duty } => {
+            write!(w, "clock {} {}", period, duty)
+        }
+        &Element::Pattern { ref bits } => {
+            write!(w, "pattern {}", pattern_to_string(bits))
+        }
+        &Element::Chip(ref chip_id, ref descr) => write!(
+            w,
+            "chip {} {} {} {} {}",
+            chip_id,
+            descr.inner_size.x,
+            descr.inner_size.y,
+            descr.left_size,
+            descr.right_size
+        ),
+    }
+}
+
+// Switches and power components have a threshold since version 3
+fn read_threshold(line: &mut Line, version: u32) -> format::Result<Threshold> {
+    if version < 3 {
+        return Ok(Threshold::default());
+    }
+
+    let threshold = Threshold {
+        on: line.next("threshold")?,
+        off: line.next("threshold")?,
+    };
+    if !threshold.is_valid() {
+        return Err(line.error(format!(
+            "invalid threshold: on {}, off {}",
+            threshold.on,
+            threshold.off
+        )));
+    }
+
+    Ok(threshold)
+}
+
+// Sources, inputs and power components have a pressure since version 4
+fn read_pressure(line: &mut Line, version: u32) -> format::Result<f64> {
+    if version < 4 {
+        return Ok(DEFAULT_PRESSURE);
+    }
+
+    let pressure: f64 = line.next("pressure")?;
+    if !(pressure >= 0.0 && pressure.is_finite()) {
+        return Err(line.error(format!("invalid pressure: {}", pressure)));
+    }
+
+    Ok(pressure)
+}
+
+fn read_element(line: &mut Line, version: u32) -> format::Result<Element> {
+    let name = line.next_str("element")?.to_string();
+
+    let element = match name.as_str() {
+        "node" => Element::Node,
+        "bridge" => Element::Bridge,
+        "switch" => {
+            let kind = line.next_str("switch type")?.to_string();
+            let kind = match kind.as_str() {
+                "on" => SwitchType::On,
+                "off" => SwitchType::Off,
+                _ => {
+                    return Err(line.error(
+                        format!("invalid switch type: `{}`", kind),
+                    ))
+                }
+            };
+            Element::Switch(kind, read_threshold(line, version)?)
+        }
+        "gate" => {
+            let kind = line.next_str("gate type")?.to_string();
+            let kind = match kind.as_str() {
+                "and" => GateType::And,
+                "or" => GateType::Or,
+                "xor" => GateType::Xor,
+                _ => {
+                    return Err(
+                        line.error(format!("invalid gate type: `{}`", kind)),
+                    )
+                }
+            };
+            Element::Gate {
+                kind: kind,
+                inputs: line.next("gate inputs")?,
+                threshold: read_threshold(line, version)?,
+            }
+        }
+        "source" => Element::Source {
+            pressure: read_pressure(line, version)?,
+        },
+        "sink" => Element::Sink,
+        "input" => Element::Input {
+            size: line.next("input size")?,
+            pressure: read_pressure(line, version)?,
+        },
+        "output" => Element::Output { size: line.next("output size")? },
+        "power" => Element::Power {
+            threshold: read_threshold(line, version)?,
+            pressure: read_pressure(line, version)?,
+        },
+        "valve" => Element::Valve,
+        "toggle" => {
+            let state = line.next_str("toggle state")?.to_string();
+            let on = match state.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    return Err(line.error(
+                        format!("invalid toggle state: `{}`", state),
+                    ))
+                }
+            };
+            Element::Toggle { on: on }
+        }
+        "reservoir" => {
+            let capacity: f64 = line.next("capacity")?;
+            if !(capacity > 0.0 && capacity.is_finite()) {
+                return Err(
+                    line.error(format!("invalid capacity: {}", capacity)),
+                );
+            }
+            Element::Reservoir { capacity: capacity }
+        }
+        "clock" => {
+            let period: usize = line.next("clock period")?;
+            let duty: usize = line.next("clock duty")?;
+            if period == 0 || duty > period {
+                return Err(line.error(format!(
+                    "invalid clock: period {}, duty {}",
+                    period,
+                    duty
+                )));
+            }
+            Element::Clock {
+                period: period,
+                duty: duty,
+            }
+        }
+        "pattern" => {
+            let bits = line.next_str("pattern bits")?.to_string();
+            let bits = bits.chars()
+                .map(|c| match c {
+                    '0' => Ok(false),
+                    '1' => Ok(true),
+                    _ => Err(()),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| {
+                    line.error(format!("invalid pattern: `{}`", bits))
+                })?;
+            Element::Pattern { bits: bits }
+        }
+        "chip" => {
+            let chip_id: ChipId = line.next("chip id")?;
+            if !is_valid_chip_id(&chip_id) {
+                return Err(
+                    line.error(format!("invalid chip id: `{}`", chip_id)),
+                );
+            }
+            let inner_x = line.next("chip width")?;
+            let inner_y = line.next("chip height")?;
+            let left_size = line.next("chip left size")?;
+            let right_size = line.next("chip right size")?;
+            let descr = ChipDescr {
+                inner_size: Coords::new(inner_x, inner_y),
+                left_size: left_size,
+                right_size: right_size,
+            };
+            Element::Chip(chip_id, descr)
+        }
+        _ => return Err(line.error(format!("unknown element: `{}`", name))),
+    };
+
+    match element {
+        Element::Input { size, .. } |
+        Element::Output { size } if size == 0 => {
+            Err(line.error(format!("{} must have at least one cell", name)))
+        }
+        Element::Gate { inputs, .. } if inputs < 2 => {
+            Err(line.error(format!("gate must have at least two inputs")))
+        }
+        _ => Ok(element),
+    }
+}
+
+impl Circuit {
+    pub fn save<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "flow circuit {}", format::VERSION)?;
+        self.write(w)
+    }
+
+    pub fn save_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.save(&mut w)?;
+        w.flush()
+    }
+
+    pub fn load<R: BufRead>(read: R) -> format::Result<Circuit> {
+        let mut reader = Reader::new(read);
+        let version = reader.header("circuit")?;
+        let circuit = Circuit::read(&mut reader, version)?;
+
+        if let Some(line) = reader.next_line()? {
+            return Err(line.error(
+                format!("unexpected `{}` after end", line.keyword()),
+            ));
+        }
+
+        Ok(circuit)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(path: P) -> format::Result<Circuit> {
+        Circuit::load(BufReader::new(File::open(path)?))
+    }
+
+    // Write the body of a circuit, without header. This is used for
+    // embedding circuits in other files.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "next_component_id {}", self.next_component_id)?;
+
+        let mut ids = self.components.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+
+        for id in ids {
+            let component = &self.components[&id];
+            write!(
+                w,
+                "component {} {} {} {} ",
+                id,
+                component.pos.x,
+                component.pos.y,
+                component.rotation_cw
+            )?;
+            write_element(w, &component.element)?;
+            writeln!(w)?;
+        }
+
+        let mut edges = self.graph
+            .edges()
+            .iter()
+            .map(|(&cells, _edge)| cells)
+            .collect::<Vec<_>>();
+        edges.sort();
+
+        for ((id_a, cell_a), (id_b, cell_b)) in edges {
+            let edge = self.graph.get_edge((id_a, cell_a), (id_b, cell_b));
+            writeln!(
+                w,
+                "edge {} {} {} {} {}",
+                id_a,
+                cell_a,
+                id_b,
+                cell_b,
+                edge.unwrap().resistance
+            )?;
+        }
+
+        writeln!(w, "end")
+    }
+
+    // Read the body of a circuit up to and including the `end` line.
+    // The derived data of the components and the points map are rebuilt,
+    // and the result is checked for consistency.
+    pub fn read<R: BufRead>(
+        reader: &mut Reader<R>,
+        version: u32,
+    ) -> format::Result<Circuit> {
+        let mut circuit = Circuit::new();
+        let mut next_component_id = None;
+
+        loop {
+            let mut line = reader.expect_line("`end`")?;
+            let keyword = line.keyword().to_string();
+
+            match keyword.as_str() {
+                "next_component_id" => {
+                    next_component_id = Some(line.next("component id")?);
+                }
+                "component" => {
+                    let id: ComponentId = line.next("component id")?;
+                    let x = line.next("x coordinate")?;
+                    let y = line.next("y coordinate")?;
+                    let rotation_cw = line.next("rotation")?;
+                    let element = read_element(&mut line, version)?;
+
+                    if circuit.components.contains_key(&id) {
+                        return Err(line.error(
+                            format!("duplicate component id {}", id),
+                        ));
+                    }
+
+                    let component =
+                        element.new_component(Coords::new(x, y), rotation_cw);
+
+                    for (i, &pos) in component.cells.iter().enumerate() {
+                        circuit.graph.add_node((id, i), pos);
+                    }
+
+                    for p in component.rect.iter() {
+                        if let Some(other_id) = circuit.points.insert(p, id) {
+                            return Err(line.error(format!(
+                                "component {} overlaps {} at ({}, {})",
+                                id,
+                                other_id,
+                                p.x,
+                                p.y
+                            )));
+                        }
+                    }
+
+                    circuit.components.insert(id, component);
+                }
+                "edge" => {
+                    let id_a = line.next("component id")?;
+                    let cell_a = line.next("cell index")?;
+                    let id_b = line.next("component id")?;
+                    let cell_b = line.next("cell index")?;
+
+                    // Edges have a resistance since version 2
+                    let resistance = if version >= 2 {
+                        line.next("resistance")?
+                    } else {
+                        DEFAULT_RESISTANCE
+                    };
+
+                    check_edge(
+                        &circuit,
+                        &line,
+                        (id_a, cell_a),
+                        (id_b, cell_b),
+                    )?;
+
+                    if !(resistance > 0.0 && resistance.is_finite()) {
+                        return Err(line.error(
+                            format!("invalid resistance: {}", resistance),
+                        ));
+                    }
+
+                    let edge = Edge { resistance: resistance };
+                    circuit.graph.add_edge(
+                        (id_a, cell_a),
+                        (id_b, cell_b),
+                        edge,
+                    );
+                }
+                "end" => {
+                    line.finish()?;
+                    break;
+                }
+                _ => {
+                    return Err(line.error(
+                        format!("unknown keyword: `{}`", keyword),
+                    ))
+                }
+            }
+
+            line.finish()?;
+        }
+
+        let min_next_id =
+            circuit.components.keys().max().map_or(0, |&id| id + 1);
+        circuit.next_component_id = match next_component_id {
+            Some(id) if id < min_next_id => {
+                return Err(format::Error::Invalid(format!(
+                    "next_component_id {} is not larger than all component ids",
+                    id
+                )));
+            }
+            Some(id) => id,
+            None => min_next_id,
+        };
+
+        Ok(circuit)
+    }
+}
+
+// Make sure that an edge connects two existing cells that are next to each
+// other, and that the components allow an edge in this direction.
+fn check_edge(
+    circuit: &Circuit,
+    line: &Line,
+    (id_a, cell_a): (ComponentId, usize),
+    (id_b, cell_b): (ComponentId, usize),
+) -> format::Result<()> {
+    let pos_a = circuit.graph.get_node((id_a, cell_a));
+    let pos_b = circuit.graph.get_node((id_b, cell_b));
+
+    let (pos_a, pos_b) = match (pos_a, pos_b) {
+        (Some(&pos_a), Some(&pos_b)) => (pos_a, pos_b),
+        _ => {
+            return Err(line.error(format!(
+                "edge refers to unknown cell ({}, {}) or ({}, {})",
+                id_a,
+                cell_a,
+                id_b,
+                cell_b
+            )))
+        }
+    };
+
+    let dir = Dir::iter().cloned().find(|dir| dir.apply(pos_a) == pos_b);
+    let valid = match dir {
+        Some(dir) => {
+            circuit.components[&id_a].get_edge_cell_index(pos_a, dir) ==
+                Some(cell_a) &&
+                circuit.components[&id_b].get_edge_cell_index(
+                    pos_b,
+                    dir.invert(),
+                ) == Some(cell_b)
+        }
+        None => false,
+    };
+
+    if !valid {
+        return Err(line.error(format!(
+            "cells ({}, {}) and ({}, {}) can not be connected",
+            id_a,
+            cell_a,
+            id_b,
+            cell_b
+        )));
+    }
+
+    if circuit.graph.get_edge((id_a, cell_a), (id_b, cell_b)).is_some() {
+        return Err(line.error(format!(
+            "duplicate edge between ({}, {}) and ({}, {})",
+            id_a,
+            cell_a,
+            id_b,
+            cell_b
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::{Action, CellId};
+
+    fn place(
+        circuit: &mut Circuit,
+        element: Element,
+        x: isize,
+        y: isize,
+        rotation_cw: usize,
+    ) -> ComponentId {
+        let component = element.new_component(Coords::new(x, y), rotation_cw);
+        let action = Action::PlaceComponent(component);
+        assert!(action.can_perform(circuit));
+        action.perform(circuit);
+        circuit.get_last_component_id().unwrap()
+    }
+
+    fn connect(circuit: &mut Circuit, id_a: CellId, id_b: CellId, r: f64) {
+        let action = Action::PlaceEdge(id_a, id_b, Edge { resistance: r });
+        assert!(action.can_perform(circuit));
+        action.perform(circuit);
+    }
+
+    fn edges(circuit: &Circuit) -> Vec<(CellId, CellId, f64)> {
+        let mut edges = circuit
+            .graph
+            .edges()
+            .iter()
+            .map(|(&(id_a, id_b), edge)| (id_a, id_b, edge.resistance))
+            .collect::<Vec<_>>();
+        edges.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        edges
+    }
+
+    #[test]
+    fn save_and_load() {
+        let descr = ChipDescr {
+            inner_size: Coords::new(4, 2),
+            left_size: 1,
+            right_size: 1,
+        };
+        let chip = Element::Chip("chip".to_string(), descr);
+        let gate = Element::Gate {
+            kind: GateType::Xor,
+            inputs: 3,
+            threshold: Threshold { on: 0.5, off: 0.25 },
+        };
+
+        // The chip is turned so that its left cell is at the top
+        let mut circuit = Circuit::new();
+        let node_a = place(&mut circuit, Element::Node, 0, -1, 0);
+        let node_b = place(&mut circuit, Element::Node, 1, -1, 0);
+        let chip_id = place(&mut circuit, chip, 0, 0, 1);
+        let node_c = place(&mut circuit, Element::Node, 0, 2, 0);
+        place(&mut circuit, gate, 3, 0, 2);
+        connect(&mut circuit, (node_a, 0), (node_b, 0), 2.5);
+        connect(&mut circuit, (node_a, 0), (chip_id, 0), 0.125);
+        connect(&mut circuit, (chip_id, 1), (node_c, 0), 4.0);
+
+        let mut saved = Vec::new();
+        circuit.save(&mut saved).unwrap();
+        let loaded = Circuit::load(&saved[..]).unwrap();
+
+        assert_eq!(loaded.next_component_id, circuit.next_component_id);
+        assert_eq!(loaded.components, circuit.components);
+        assert_eq!(edges(&loaded), edges(&circuit));
+        assert_eq!(loaded.points, circuit.points);
+
+        // Saving again gives the same file
+        let mut saved_again = Vec::new();
+        loaded.save(&mut saved_again).unwrap();
+        assert_eq!(saved_again, saved);
+    }
+
+    #[test]
+    fn load_malformed() {
+        let files: &[&str] = &[
+            "",
+            "flow level 5\nend\n",
+            "flow circuit 99\nend\n",
+            "flow circuit 5\n",
+            "flow circuit 5\nwire 0 0\nend\n",
+            "flow circuit 5\ncomponent 0 0 0\nend\n",
+            "flow circuit 5\ncomponent 0 0 zero 0 node\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node 1\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 gate and 1 0.5 0.25\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 chip a/b 1 1 1 1\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node\n\
+             component 0 1 0 0 node\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node\n\
+             component 1 0 0 0 node\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node\nedge 0 0 1 0 1\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node\n\
+             component 1 2 0 0 node\nedge 0 0 1 0 1\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node\n\
+             component 1 1 0 0 node\nedge 0 0 1 0 0\nend\n",
+            "flow circuit 5\ncomponent 0 0 0 0 node\n\
+             component 1 1 0 0 node\nedge 0 0 1 3 1\nend\n",
+            "flow circuit 5\nend\nend\n",
+        ];
+
+        for file in files.iter() {
+            assert!(Circuit::load(file.as_bytes()).is_err(), "{}", file);
+        }
+    }
+}
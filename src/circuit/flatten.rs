@@ -0,0 +1,811 @@
+use std::collections::{HashMap, HashSet};
+use std::cmp;
+
+use types::{Dir, Rect};
+
+use super::{Coords, CellId, ComponentId, ChipId, Chip, ChipDb, Circuit,
+            Component, Edge, Element, Action, Hierarchy, UnfoldError};
+
+// Flattened circuits of the chips that have been needed so far, with the
+// hierarchy of the chip instances inside them
+type FlattenedChips = HashMap<ChipId, (Circuit, Hierarchy)>;
+
+// A cell of a chip component before flattening
+struct Pin {
+    side: Dir,
+    y: isize,
+
+    // Cell of the input component inside the chip that this cell is glued to
+    inner_cell_id: CellId,
+
+    // Cell outside of the chip that this cell is connected to
+    outer: Option<(CellId, Edge)>,
+}
+
+impl Circuit {
+    // Flatten this circuit by recursively instantiating chips, like unfold.
+    // Unlike unfold, the result is a circuit with valid positions, which can
+    // be shown, saved and edited like any other circuit:
+    // - Each chip component makes room for the flattened circuit of its chip
+    //   by moving everything to the right of its left column further to the
+    //   right, see place_unrotated_chip.
+    // - The input components inside the chip become nodes.
+    // - Edges that are cut by this, and the edges of the chip component, are
+    //   replaced by wires, that is chains of nodes whose edges together have
+    //   the resistance of the replaced edge.
+    // The Hierarchy relates the cells to the chip instances like for unfold,
+    // where the cells of a chip component are glued to the input nodes.
+    // Besides the errors of unfold, this fails with UnfoldError::NoRoute if
+    // a chip has other components on the outer side of a connected input
+    // cell, if something that is connected vertically is in the way of the
+    // new columns, or if the wires can not be laid out along neighboring
+    // cells for some other reason.
+    pub fn flatten(
+        &self,
+        chip_db: &ChipDb,
+    ) -> Result<(Circuit, Hierarchy), UnfoldError> {
+        flatten(self, chip_db, &mut Vec::new(), &mut HashMap::new())
+    }
+}
+
+// Flatten a circuit that is nested in the chips of `chip_path`. The chips are
+// flattened only once, and then placed for each of their instances.
+fn flatten(
+    circuit: &Circuit,
+    chip_db: &ChipDb,
+    chip_path: &mut Vec<ChipId>,
+    flattened_chips: &mut FlattenedChips,
+) -> Result<(Circuit, Hierarchy), UnfoldError> {
+    let mut flat = circuit.clone();
+    let mut hierarchy = Hierarchy::new();
+
+    let mut chip_component_ids = Vec::new();
+    for (&id, component) in circuit.components.iter() {
+        match component.element {
+            Element::Chip(_, _) => chip_component_ids.push(id),
+            _ => {
+                hierarchy.add_component(id, component.cells.len(), &vec![], id)
+            }
+        }
+    }
+
+    // The ids of the new nodes depend on the order of the chips
+    chip_component_ids.sort();
+
+    for &chip_component_id in chip_component_ids.iter() {
+        let chip_component = &circuit.components[&chip_component_id];
+        let chip_id = match chip_component.element {
+            Element::Chip(ref chip_id, _) => chip_id.clone(),
+            _ => panic!("component should be a Chip"),
+        };
+        let chip = match chip_db.get(&chip_id) {
+            Some(chip) => chip,
+            None => return Err(UnfoldError::MissingChip(chip_id)),
+        };
+        super::check_pins(&chip_id, &chip_component.element.descr(), chip)?;
+
+        let cycle_start = chip_path.iter().position(|id| *id == chip_id);
+        if let Some(cycle_start) = cycle_start {
+            let mut cycle = chip_path.split_off(cycle_start);
+            cycle.push(chip_id);
+            return Err(UnfoldError::Cycle(cycle));
+        }
+
+        if !flattened_chips.contains_key(&chip_id) {
+            chip_path.push(chip_id.clone());
+            let flattened_chip =
+                flatten(&chip.circuit, chip_db, chip_path, flattened_chips)?;
+            chip_path.pop();
+
+            flattened_chips.insert(chip_id.clone(), flattened_chip);
+        }
+
+        let &(ref inner, ref inner_hierarchy) = &flattened_chips[&chip_id];
+        let (cell_map, pin_cell_ids) =
+            match place_chip(&mut flat, chip_component_id, inner, chip) {
+                Some(placed) => placed,
+                None => return Err(UnfoldError::NoRoute(chip_id)),
+            };
+
+        hierarchy.add_instance(
+            &vec![chip_component_id],
+            inner_hierarchy,
+            |cell_id| cell_map[&cell_id],
+        );
+        for (cell_index, &cell_id) in pin_cell_ids.iter().enumerate() {
+            hierarchy.glue(&vec![], (chip_component_id, cell_index), cell_id);
+        }
+    }
+
+    Ok((flat, hierarchy))
+}
+
+// Replace a chip component by the flattened circuit of its chip. Returns the
+// cells that the cells of the flattened chip circuit have become, and the
+// cells that the cells of the chip component are glued to.
+fn place_chip(
+    flat: &mut Circuit,
+    chip_component_id: ComponentId,
+    inner: &Circuit,
+    chip: &Chip,
+) -> Option<(HashMap<CellId, CellId>, Vec<CellId>)> {
+    // Turn everything such that the chip component is not rotated, which
+    // leaves a single case for making room
+    let rotation_cw = flat.components[&chip_component_id].rotation_cw % 4;
+
+    rotate(flat, 4 - rotation_cw);
+    let placed = place_unrotated_chip(flat, chip_component_id, inner, chip);
+    rotate(flat, rotation_cw);
+
+    placed
+}
+
+// Make room for the flattened chip circuit by inserting columns at a cut
+// through the chip component, see cut_columns. The new columns are laid out
+// like this:
+//
+//     cut | left channel | chip circuit | right channel | cut + width
+//
+// The wires run from left to right in their row. In a channel, each wire
+// that needs to change its row gets a column of its own for that. Wires that
+// cross the chip circuit go over or under it. Wires of the chip component
+// lead to the input nodes from the outer side, and the input cells are
+// checked for this to be free. Since the wires keep their order from top to
+// bottom, they never cross.
+fn place_unrotated_chip(
+    flat: &mut Circuit,
+    chip_component_id: ComponentId,
+    inner: &Circuit,
+    chip: &Chip,
+) -> Option<(HashMap<CellId, CellId>, Vec<CellId>)> {
+    let chip_component = flat.components[&chip_component_id].clone();
+    let chip_rect = chip_component.rect;
+    let x0 = chip_rect.pos.x;
+
+    let descr = chip_component.element.descr();
+    let mut pins = Vec::new();
+    let mut num_cells = HashMap::new();
+    for (cell_index, &(side, _k)) in descr.cells.iter().enumerate() {
+        let input_id = if side == Dir::Left {
+            chip.left_input_id
+        } else {
+            chip.right_input_id
+        };
+        let inner_cell_index = num_cells.entry(side).or_insert(0);
+
+        let cell_id = (chip_component_id, cell_index);
+        let outer = flat.graph.get_neighbors(cell_id).unwrap().first().map(
+            |&neighbor_id| {
+                let edge = flat.graph.get_edge(cell_id, neighbor_id).unwrap();
+                (neighbor_id, *edge)
+            },
+        );
+
+        pins.push(Pin {
+            side: side,
+            y: chip_component.cells[cell_index].y,
+            inner_cell_id: (input_id, *inner_cell_index),
+            outer: outer,
+        });
+        *inner_cell_index += 1;
+    }
+
+    let inner_rect = match bounding_rect(inner.points.keys()) {
+        Some(rect) => rect,
+        None => {
+            // Nothing to place
+            Action::RemoveComponentAtPos(chip_component.pos).perform(flat);
+            return Some((HashMap::new(), Vec::new()));
+        }
+    };
+    let inner_pos = |cell_id| *inner.graph.get_node(cell_id).unwrap();
+
+    // Wires of connected pins need to reach the input cells from outside,
+    // without changing their order
+    for &side in [Dir::Left, Dir::Right].iter() {
+        let mut connected = pins.iter()
+            .filter(|pin| pin.side == side && pin.outer.is_some())
+            .map(|pin| (pin.y, inner_pos(pin.inner_cell_id)))
+            .collect::<Vec<_>>();
+        connected.sort_by_key(|&(y, _p)| y);
+
+        if connected.windows(2).any(|w| w[0].1.y >= w[1].1.y) {
+            return None;
+        }
+
+        let blocked = connected.iter().any(|&(_y, p)| {
+            let xs = if side == Dir::Left {
+                inner_rect.pos.x..p.x
+            } else {
+                p.x + 1..inner_rect.pos.x + inner_rect.size.x + 1
+            };
+            xs.map(|x| Coords::new(x, p.y))
+                .any(|q| inner.points.contains_key(&q))
+        });
+        if blocked {
+            return None;
+        }
+    }
+
+    // Align the chip circuit vertically with the pins, if possible
+    let offset_y = pins.iter()
+        .find(|pin| pin.outer.is_some())
+        .map_or(chip_rect.pos.y - inner_rect.pos.y, |pin| {
+            pin.y - inner_pos(pin.inner_cell_id).y
+        });
+    let inner_top = inner_rect.pos.y + offset_y;
+    let inner_bottom = inner_top + inner_rect.size.y;
+
+    // Cut between the columns of the chip component, or next to it if
+    // something is in the way
+    let cut = [x0, x0 - 1, x0 + 1]
+        .iter()
+        .filter_map(|&cut_x| {
+            cut_columns(flat, chip_component_id, cut_x)
+                .map(|(moved, crossings)| (cut_x, moved, crossings))
+        })
+        .next();
+    let (cut_x, moved, crossings) = match cut {
+        Some(cut) => cut,
+        None => return None,
+    };
+
+    // Rows in which the crossing wires pass the chip circuit, closest first
+    let mut pass_rows = HashMap::new();
+    let mut next_row = inner_top - 1;
+    for &(y, _, _, _) in crossings.iter().rev() {
+        if y < chip_rect.pos.y {
+            let row = cmp::min(y, next_row);
+            pass_rows.insert(y, row);
+            next_row = row - 1;
+        }
+    }
+    let mut next_row = inner_bottom + 1;
+    for &(y, _, _, _) in crossings.iter() {
+        if y > chip_rect.pos.y + chip_rect.size.y {
+            let row = cmp::max(y, next_row);
+            pass_rows.insert(y, row);
+            next_row = row + 1;
+        }
+    }
+
+    // Rows in which each wire enters and leaves the channels. The crossings
+    // come first, then the connected pins.
+    let connected_pins = pins.iter()
+        .filter(|pin| pin.outer.is_some())
+        .collect::<Vec<_>>();
+    let pin_row = |pin: &Pin| inner_pos(pin.inner_cell_id).y + offset_y;
+    let left_rows = crossings
+        .iter()
+        .map(|&(y, _, _, _)| (y, pass_rows[&y]))
+        .chain(
+            connected_pins
+                .iter()
+                .filter(|pin| pin.side == Dir::Left)
+                .map(|pin| (pin.y, pin_row(pin))),
+        )
+        .collect::<Vec<_>>();
+    let right_rows = crossings
+        .iter()
+        .map(|&(y, _, _, _)| (pass_rows[&y], y))
+        .chain(
+            connected_pins
+                .iter()
+                .filter(|pin| pin.side == Dir::Right)
+                .map(|pin| (pin_row(pin), pin.y)),
+        )
+        .collect::<Vec<_>>();
+    let (left_columns, num_left_columns) = channel_columns(&left_rows);
+    let (right_columns, num_right_columns) = channel_columns(&right_rows);
+
+    let inner_width = inner_rect.size.x + 1;
+    let width = num_left_columns as isize + inner_width +
+        num_right_columns as isize + 1;
+    let inner_x = cut_x + 1 + num_left_columns as isize;
+    let offset = Coords::new(inner_x - inner_rect.pos.x, offset_y);
+
+    // Now that everything fits, start changing the circuit
+    for &(_y, left_id, right_id, _edge) in crossings.iter() {
+        flat.graph.remove_edge(left_id, right_id);
+    }
+    Action::RemoveComponentAtPos(chip_component.pos).perform(flat);
+
+    for &id in moved.iter() {
+        let component = flat.components.get_mut(&id).unwrap();
+        let shift = Coords::new(width, 0);
+
+        component.pos += shift;
+        component.rect.pos += shift;
+        for cell_pos in component.cells.iter_mut() {
+            *cell_pos += shift;
+        }
+    }
+    update_positions(flat);
+
+    // Place the chip circuit, with nodes in place of the inputs
+    let mut cell_map = HashMap::new();
+    let mut inner_ids = inner.components.keys().cloned().collect::<Vec<_>>();
+    inner_ids.sort();
+
+    for &id in inner_ids.iter() {
+        let component = &inner.components[&id];
+
+        if id == chip.left_input_id || id == chip.right_input_id {
+            for (cell_index, &cell_pos) in component.cells.iter().enumerate() {
+                let node = Element::Node.new_component(cell_pos + offset, 0);
+                let node_id = place_component(flat, node)?;
+                cell_map.insert((id, cell_index), (node_id, 0));
+            }
+        } else {
+            let mut new_component = component.clone();
+            new_component.pos += offset;
+            new_component.rect.pos += offset;
+            for cell_pos in new_component.cells.iter_mut() {
+                *cell_pos += offset;
+            }
+
+            let new_id = place_component(flat, new_component)?;
+            for cell_index in 0..component.cells.len() {
+                cell_map.insert((id, cell_index), (new_id, cell_index));
+            }
+        }
+    }
+
+    for (id_a, id_b) in inner.graph.sorted_edge_ids() {
+        let edge = *inner.graph.get_edge(id_a, id_b).unwrap();
+        add_edge(flat, cell_map[&id_a], cell_map[&id_b], edge)?;
+    }
+
+    // Route the wires
+    let row = |x_start: isize, x_end: isize, y: isize| {
+        (x_start..x_end).map(|x| Coords::new(x, y)).collect::<Vec<_>>()
+    };
+    let right_x = inner_x + inner_width;
+
+    for (i, &(y, left_id, right_id, edge)) in crossings.iter().enumerate() {
+        let left_x = flat.graph.get_node(left_id).unwrap().x;
+        let right_x_end = flat.graph.get_node(right_id).unwrap().x;
+
+        let mut path = row(left_x + 1, cut_x + 1, y);
+        path.extend(channel_path(
+            cut_x + 1,
+            num_left_columns,
+            left_rows[i],
+            left_columns[i],
+        ));
+        path.extend(row(inner_x, right_x, pass_rows[&y]));
+        path.extend(channel_path(
+            right_x,
+            num_right_columns,
+            right_rows[i],
+            right_columns[i],
+        ));
+        path.extend(row(cut_x + width, right_x_end, y));
+
+        add_wire(flat, left_id, &path, right_id, edge)?;
+    }
+
+    let mut num_left = 0;
+    let mut num_right = 0;
+    for pin in connected_pins.iter() {
+        let (outer_id, edge) = pin.outer.unwrap();
+        let outer_x = flat.graph.get_node(outer_id).unwrap().x;
+        let inner_cell_id = cell_map[&pin.inner_cell_id];
+        let p = inner_pos(pin.inner_cell_id) + offset;
+
+        if pin.side == Dir::Left {
+            let i = crossings.len() + num_left;
+            num_left += 1;
+
+            let mut path = row(outer_x + 1, cut_x + 1, pin.y);
+            path.extend(channel_path(
+                cut_x + 1,
+                num_left_columns,
+                left_rows[i],
+                left_columns[i],
+            ));
+            path.extend(row(inner_x, p.x, p.y));
+
+            add_wire(flat, outer_id, &path, inner_cell_id, edge)?;
+        } else {
+            let i = crossings.len() + num_right;
+            num_right += 1;
+
+            let mut path = row(p.x + 1, right_x, p.y);
+            path.extend(channel_path(
+                right_x,
+                num_right_columns,
+                right_rows[i],
+                right_columns[i],
+            ));
+            path.extend(row(cut_x + width, outer_x, pin.y));
+
+            add_wire(flat, inner_cell_id, &path, outer_id, edge)?;
+        }
+    }
+
+    let pin_cell_ids = pins.iter()
+        .map(|pin| cell_map[&pin.inner_cell_id])
+        .collect();
+
+    Some((cell_map, pin_cell_ids))
+}
+
+// Find the components that are moved when inserting columns to the right of
+// column cut_x, and the edges that cross the cut, from left to right.
+// Components that start in column cut_x are moved as a whole, which works as
+// long as all crossing edges are horizontal. Other components must not be
+// cut.
+fn cut_columns(
+    flat: &Circuit,
+    chip_component_id: ComponentId,
+    cut_x: isize,
+) -> Option<(Vec<ComponentId>, Vec<(isize, CellId, CellId, Edge)>)> {
+    let mut moved = HashSet::new();
+    for (&id, c) in flat.components.iter() {
+        if id != chip_component_id && c.rect.pos.x + c.rect.size.x > cut_x {
+            if c.rect.pos.x < cut_x {
+                return None;
+            }
+            moved.insert(id);
+        }
+    }
+
+    let mut crossings = Vec::new();
+    for (&(id_a, id_b), &edge) in flat.graph.edges().iter() {
+        if id_a.0 == chip_component_id || id_b.0 == chip_component_id ||
+            moved.contains(&id_a.0) == moved.contains(&id_b.0)
+        {
+            continue;
+        }
+
+        let (left_id, right_id) = if moved.contains(&id_b.0) {
+            (id_a, id_b)
+        } else {
+            (id_b, id_a)
+        };
+        let y = flat.graph.get_node(left_id).unwrap().y;
+        if flat.graph.get_node(right_id).unwrap().y != y {
+            return None;
+        }
+
+        crossings.push((y, left_id, right_id, edge));
+    }
+    crossings.sort_by_key(|&(y, left_id, _, _)| (y, left_id));
+
+    Some((moved.into_iter().collect(), crossings))
+}
+
+// Assign the columns of a channel to the wires that need to change their row
+// in it. Each wire enters the channel in the first row and leaves it in the
+// second row. Wires that go up are given columns from top to bottom, and
+// wires that go down from bottom to top, so that they do not cross.
+// Returns the column of each wire, and the number of columns.
+fn channel_columns(rows: &[(isize, isize)]) -> (Vec<Option<usize>>, usize) {
+    let mut up = (0..rows.len())
+        .filter(|&i| rows[i].1 < rows[i].0)
+        .collect::<Vec<_>>();
+    up.sort_by_key(|&i| rows[i].0);
+
+    let mut down = (0..rows.len())
+        .filter(|&i| rows[i].1 > rows[i].0)
+        .collect::<Vec<_>>();
+    down.sort_by_key(|&i| -rows[i].0);
+
+    let mut columns = vec![None; rows.len()];
+    for (column, &i) in up.iter().chain(down.iter()).enumerate() {
+        columns[i] = Some(column);
+    }
+
+    (columns, up.len() + down.len())
+}
+
+// Points of a wire in a channel starting at column x
+fn channel_path(
+    x: isize,
+    num_columns: usize,
+    (start_y, end_y): (isize, isize),
+    column: Option<usize>,
+) -> Vec<Coords> {
+    let mut path = Vec::new();
+
+    for i in 0..num_columns {
+        let p = Coords::new(x + i as isize, start_y);
+
+        match column {
+            Some(column) if i == column => {
+                let dir = if end_y < start_y { Dir::Up } else { Dir::Down };
+                let steps = (end_y - start_y).abs() as usize;
+                path.extend((0..steps + 1).map(|n| dir.apply_n(p, n)));
+            }
+            Some(column) if i > column => path.push(Coords::new(p.x, end_y)),
+            _ => path.push(p),
+        }
+    }
+
+    path
+}
+
+// Connect two cells through nodes at the given points. The edges share the
+// resistance of the edge that the wire replaces. Fails if the points are
+// taken, or if they do not form a path of neighboring cells.
+fn add_wire(
+    circuit: &mut Circuit,
+    from_id: CellId,
+    path: &[Coords],
+    to_id: CellId,
+    edge: Edge,
+) -> Option<()> {
+    let edge = Edge { resistance: edge.resistance / (path.len() + 1) as f64 };
+    let mut prev_id = from_id;
+
+    for &p in path.iter() {
+        let node_id =
+            place_component(circuit, Element::Node.new_component(p, 0))?;
+        add_edge(circuit, prev_id, (node_id, 0), edge)?;
+        prev_id = (node_id, 0);
+    }
+
+    add_edge(circuit, prev_id, to_id, edge)
+}
+
+// Add an edge, but only between neighboring cells that allow an edge in that
+// direction, so that the flattened circuit can be saved and loaded again
+fn add_edge(
+    circuit: &mut Circuit,
+    id_a: CellId,
+    id_b: CellId,
+    edge: Edge,
+) -> Option<()> {
+    let pos_a = *circuit.graph.get_node(id_a)?;
+    let pos_b = *circuit.graph.get_node(id_b)?;
+    let component_a = &circuit.components[&id_a.0];
+    let component_b = &circuit.components[&id_b.0];
+
+    let valid = Dir::iter().any(|&dir| {
+        dir.apply(pos_a) == pos_b &&
+            component_a.get_edge_cell_index(pos_a, dir) == Some(id_a.1) &&
+            component_b.get_edge_cell_index(pos_b, dir.invert()) ==
+                Some(id_b.1)
+    });
+    if !valid || circuit.graph.get_edge(id_a, id_b).is_some() {
+        return None;
+    }
+
+    circuit.graph.add_edge(id_a, id_b, edge);
+    Some(())
+}
+
+fn place_component(
+    circuit: &mut Circuit,
+    component: Component,
+) -> Option<ComponentId> {
+    let action = Action::PlaceComponent(component);
+    if !action.can_perform(circuit) {
+        return None;
+    }
+
+    action.perform(circuit);
+    circuit.get_last_component_id()
+}
+
+fn bounding_rect<'a, I>(points: I) -> Option<Rect>
+where
+    I: Iterator<Item = &'a Coords>,
+{
+    points.fold(None, |rect, &p| match rect {
+        None => Some(Rect::from_coords(p, p)),
+        Some(rect) => {
+            let corner = rect.pos + rect.size;
+            let min = Coords::new(
+                cmp::min(rect.pos.x, p.x),
+                cmp::min(rect.pos.y, p.y),
+            );
+            let max =
+                Coords::new(cmp::max(corner.x, p.x), cmp::max(corner.y, p.y));
+            Some(Rect::from_coords(min, max))
+        }
+    })
+}
+
+// Rotate the whole circuit clockwise around the origin, n times
+fn rotate(circuit: &mut Circuit, n: usize) {
+    for _ in 0..n % 4 {
+        for component in circuit.components.values_mut() {
+            let turn = |p: Coords| Coords::new(-p.y, p.x);
+            let corner = component.rect.pos + component.rect.size;
+            let rect =
+                Rect::from_coords(turn(component.rect.pos), turn(corner));
+            let rotation_cw = (component.rotation_cw + 1) % 4;
+
+            *component = component.element.new_component(rect.pos, rotation_cw);
+        }
+    }
+
+    update_positions(circuit);
+}
+
+// Bring the cells of the graph and the points in line with the components
+fn update_positions(circuit: &mut Circuit) {
+    circuit.points.clear();
+
+    for (&id, component) in circuit.components.iter() {
+        for (cell_index, &cell_pos) in component.cells.iter().enumerate() {
+            let node = circuit.graph.nodes.get_mut(&(id, cell_index)).unwrap();
+            node.0 = cell_pos;
+        }
+        for p in component.rect.iter() {
+            circuit.points.insert(p, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::{ChipDescr, DEFAULT_PRESSURE};
+    use flow;
+
+    fn place(circuit: &mut Circuit, element: Element, x: isize, y: isize) {
+        let component = element.new_component(Coords::new(x, y), 0);
+        let action = Action::PlaceComponent(component);
+        assert!(action.can_perform(circuit));
+        action.perform(circuit);
+    }
+
+    // Connect each of the given columns in row y to the next column
+    fn connect(circuit: &mut Circuit, xs: &[isize], y: isize) {
+        for &x in xs.iter() {
+            let edge = Edge { resistance: 1.0 + x as f64 };
+            let action = Action::PlaceEdgeAtPos(
+                Coords::new(x, y),
+                Dir::Right,
+                Some(edge),
+            );
+            assert!(action.can_perform(circuit));
+            action.perform(circuit);
+        }
+    }
+
+    fn chip_element(chip_db: &ChipDb, id: &str) -> Element {
+        let id = id.to_string();
+        let descr = chip_db.get_descr(&id).unwrap().clone();
+        Element::Chip(id, descr)
+    }
+
+    // A library with a chip `inner` that connects its input cells through
+    // nodes, and a chip `outer` that does the same through `inner`
+    fn chip_db() -> ChipDb {
+        let descr = ChipDescr {
+            inner_size: Coords::new(5, 0),
+            left_size: 1,
+            right_size: 1,
+        };
+        let mut chip_db = ChipDb::new("chips");
+        chip_db.add("inner".to_string(), descr.clone()).unwrap();
+        chip_db.add("outer".to_string(), descr).unwrap();
+
+        {
+            let inner = chip_db.get_circuit_mut(&"inner".to_string()).unwrap();
+            for x in 1..5 {
+                place(inner, Element::Node, x, 0);
+            }
+            connect(inner, &[0, 1, 2, 3, 4], 0);
+        }
+
+        let chip = chip_element(&chip_db, "inner");
+        {
+            let outer = chip_db.get_circuit_mut(&"outer".to_string()).unwrap();
+            place(outer, Element::Node, 1, 0);
+            place(outer, chip, 2, 0);
+            place(outer, Element::Node, 4, 0);
+            connect(outer, &[0, 1, 3, 4], 0);
+        }
+
+        chip_db
+    }
+
+    // A source and a sink that are connected through a chip component, and
+    // a wire that passes above the chip component. The source and the sink
+    // are either next to the chip component, or connected to it through
+    // nodes. The circuit is rotated as a whole. Returns the id of the sink.
+    fn circuit(
+        chip_db: &ChipDb,
+        chip_id: &str,
+        direct: bool,
+        rotation_cw: usize,
+    ) -> (Circuit, ComponentId) {
+        let mut circuit = Circuit::new();
+        let source = Element::Source { pressure: DEFAULT_PRESSURE };
+        let chip = chip_element(chip_db, chip_id);
+
+        if direct {
+            place(&mut circuit, source, 1, 0);
+            place(&mut circuit, chip, 2, 0);
+            place(&mut circuit, Element::Sink, 4, 0);
+            connect(&mut circuit, &[1, 3], 0);
+        } else {
+            place(&mut circuit, source, 0, 0);
+            place(&mut circuit, Element::Node, 1, 0);
+            place(&mut circuit, chip, 2, 0);
+            place(&mut circuit, Element::Node, 4, 0);
+            place(&mut circuit, Element::Sink, 5, 0);
+            connect(&mut circuit, &[0, 1, 3, 4], 0);
+        }
+        let sink_id = circuit.get_last_component_id().unwrap();
+
+        for x in 0..6 {
+            place(&mut circuit, Element::Node, x, -1);
+        }
+        connect(&mut circuit, &[0, 1, 2, 3, 4], -1);
+
+        rotate(&mut circuit, rotation_cw);
+        (circuit, sink_id)
+    }
+
+    // Flow into a sink after a few ticks
+    fn sink_flow(mut state: flow::State, sink_id: ComponentId) -> f64 {
+        for _ in 0..3 {
+            flow::time_step(&mut state, 0.1);
+        }
+
+        let node_idx = state.graph.node_index((sink_id, 0));
+        state.flow.node(node_idx).in_flow
+    }
+
+    #[test]
+    fn flatten_saves_and_simulates() {
+        let chip_db = chip_db();
+
+        for &chip_id in ["inner", "outer"].iter() {
+            for &direct in [false, true].iter() {
+                for rotation_cw in 0..4 {
+                    let (circuit, sink_id) =
+                        circuit(&chip_db, chip_id, direct, rotation_cw);
+                    let (flat, _) = circuit.flatten(&chip_db).unwrap();
+
+                    let mut file = Vec::new();
+                    flat.save(&mut file).unwrap();
+                    let loaded = Circuit::load(file.as_slice()).unwrap();
+                    assert_eq!(
+                        loaded.components().len(),
+                        flat.components().len()
+                    );
+
+                    let (state, _) =
+                        flow::State::unfold(&circuit, &chip_db).unwrap();
+                    let expected = sink_flow(state, sink_id);
+                    let state = flow::State::from_circuit(&loaded);
+                    let flow = sink_flow(state, sink_id);
+
+                    assert!(expected > 0.0);
+                    assert!(
+                        (flow - expected).abs() < 1e-9,
+                        "{} rotated {} times: {} != {}",
+                        chip_id,
+                        rotation_cw,
+                        flow,
+                        expected
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flatten_no_route() {
+        let mut chip_db = chip_db();
+        let (circuit, _) = circuit(&chip_db, "outer", false, 0);
+
+        // The wire to the left input of the chip would have to pass the node
+        {
+            let inner = chip_db.get_circuit_mut(&"inner".to_string()).unwrap();
+            place(inner, Element::Node, -1, 0);
+        }
+
+        match circuit.flatten(&chip_db) {
+            Err(UnfoldError::NoRoute(ref chip_id)) if chip_id == "inner" => {}
+            _ => panic!("expected NoRoute"),
+        }
+    }
+}
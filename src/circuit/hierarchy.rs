@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use super::{ComponentId, CellId};
+
+// Chip components that lead to a chip instance, starting in the circuit that
+// was unfolded. Each id refers to a component in the circuit of the chip
+// before it, so the ids are the ones that are shown in the editor. The empty
+// path is the unfolded circuit itself.
+pub type InstancePath = Vec<ComponentId>;
+
+// Relates the cells of an unfolded circuit to the chip instances they come
+// from, see Circuit::unfold and Circuit::flatten.
+#[derive(Clone, Debug, Default)]
+pub struct Hierarchy {
+    // Instance and cell in the circuit of that instance, for each cell of the
+    // unfolded circuit. The nodes that flattening adds for wires have none.
+    origins: HashMap<CellId, (InstancePath, CellId)>,
+
+    // The other way around. This also contains the cells of the input
+    // components of chips, which are glued to the cells of the chip component
+    // in the unfolded circuit.
+    cells: HashMap<(InstancePath, CellId), CellId>,
+}
+
+impl Hierarchy {
+    pub fn new() -> Hierarchy {
+        Hierarchy::default()
+    }
+
+    // Record that a component of the unfolded circuit has been created from
+    // the given component of a chip instance
+    pub fn add_component(
+        &mut self,
+        id: ComponentId,
+        num_cells: usize,
+        path: &InstancePath,
+        origin_id: ComponentId,
+    ) {
+        for cell_index in 0..num_cells {
+            self.add_cell((id, cell_index), path, (origin_id, cell_index));
+        }
+    }
+
+    // Record that a single cell of the unfolded circuit has been created from
+    // the given cell of a chip instance
+    pub fn add_cell(
+        &mut self,
+        cell_id: CellId,
+        path: &InstancePath,
+        origin_cell_id: CellId,
+    ) {
+        self.origins.insert(cell_id, (path.clone(), origin_cell_id));
+        self.cells.insert((path.clone(), origin_cell_id), cell_id);
+    }
+
+    // Record the cells of a chip instance at once, given the hierarchy of the
+    // circuit of the chip by itself. `cell_map` tells which cell of the
+    // unfolded circuit each cell of that circuit has become.
+    pub fn add_instance<F>(
+        &mut self,
+        path: &InstancePath,
+        inner: &Hierarchy,
+        cell_map: F,
+    ) where
+        F: Fn(CellId) -> CellId,
+    {
+        let prefixed = |inner_path: &InstancePath| {
+            path.iter().chain(inner_path.iter()).cloned().collect::<Vec<_>>()
+        };
+
+        for (&cell_id, &(ref inner_path, origin_cell_id)) in
+            inner.origins.iter()
+        {
+            self.origins.insert(
+                cell_map(cell_id),
+                (prefixed(inner_path), origin_cell_id),
+            );
+        }
+        for (&(ref inner_path, origin_cell_id), &cell_id) in
+            inner.cells.iter()
+        {
+            self.cells.insert(
+                (prefixed(inner_path), origin_cell_id),
+                cell_map(cell_id),
+            );
+        }
+    }
+
+    // Record that a cell of a chip instance has been glued to a cell of the
+    // unfolded circuit, without being part of it itself
+    pub fn glue(
+        &mut self,
+        path: &InstancePath,
+        origin_cell_id: CellId,
+        cell_id: CellId,
+    ) {
+        self.cells.insert((path.clone(), origin_cell_id), cell_id);
+    }
+
+    // Instance and cell in the circuit of that instance that a cell of the
+    // unfolded circuit comes from
+    pub fn origin(&self, cell_id: CellId) -> Option<&(InstancePath, CellId)> {
+        self.origins.get(&cell_id)
+    }
+
+    // Cell of the unfolded circuit that a cell of a chip instance has become
+    pub fn cell_id(
+        &self,
+        path: &InstancePath,
+        origin_cell_id: CellId,
+    ) -> Option<CellId> {
+        self.cells.get(&(path.clone(), origin_cell_id)).cloned()
+    }
+
+    // Component of the unfolded circuit that a component of a chip instance
+    // has become, if it has not been glued to another one
+    pub fn component_id(
+        &self,
+        path: &InstancePath,
+        origin_id: ComponentId,
+    ) -> Option<ComponentId> {
+        self.cell_id(path, (origin_id, 0)).and_then(|cell_id| {
+            match self.origin(cell_id) {
+                Some(&(ref origin_path, (id, _))) if origin_path == path &&
+                    id == origin_id => Some(cell_id.0),
+                _ => None,
+            }
+        })
+    }
+}
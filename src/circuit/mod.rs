@@ -1,18 +1,28 @@
 mod action;
 mod component;
 mod chip_db;
+mod file;
+mod flatten;
+mod hierarchy;
+mod template;
 
 use std::collections::{HashMap, HashSet};
 use std::cmp;
+use std::error;
+use std::fmt;
 
 use types::{Dir, Rect};
 use graph::NeighborGraph;
 
 pub use types::Coords;
 pub use self::action::Action;
-pub use self::component::{SwitchType, ChipId, ChipDescr, ElementDescr,
-                          Element, Component};
-pub use self::chip_db::{Chip, ChipDb};
+pub use self::component::{SwitchType, GateType, Threshold, DEFAULT_THRESHOLD,
+                          DEFAULT_PRESSURE, DEFAULT_CAPACITY, ChipId,
+                          ChipDescr, ElementDescr, Element, Component,
+                          pattern_to_string};
+pub use self::chip_db::{Chip, ChipDb, is_valid_chip_id};
+pub use self::hierarchy::{InstancePath, Hierarchy};
+pub use self::template::UnfoldTarget;
 
 pub type ComponentId = usize;
 
@@ -21,8 +31,82 @@ pub type ComponentId = usize;
 // element.
 pub type CellId = (ComponentId, usize);
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
-pub struct Edge {}
+// Resistance of edges that are drawn without changing the resistance
+pub const DEFAULT_RESISTANCE: f64 = 1.0;
+
+// Range of resistances that can be chosen in the editor. Halving or doubling
+// the resistance without bounds would end up at zero or infinity, which the
+// solver can not handle.
+pub const MIN_RESISTANCE: f64 = 1.0 / 1024.0;
+pub const MAX_RESISTANCE: f64 = 1024.0;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Edge {
+    // Ratio between the pressure difference of the two cells and the
+    // velocity of the flow through the edge. Must be positive.
+    pub resistance: f64,
+}
+
+impl Default for Edge {
+    fn default() -> Edge {
+        Edge { resistance: DEFAULT_RESISTANCE }
+    }
+}
+
+// Reasons why a circuit can not be unfolded
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum UnfoldError {
+    // Chips that contain each other. The first and the last chip are the
+    // same, and each chip contains the next one.
+    Cycle(Vec<ChipId>),
+
+    // A chip that is not in the ChipDb
+    MissingChip(ChipId),
+
+    // The description of a chip component has a different number of cells on
+    // one side than the input component of that side inside the chip
+    PinMismatch {
+        chip_id: ChipId,
+        side: Dir,
+        descr_size: usize,
+        input_size: usize,
+    },
+
+    // Flattening found no room to connect the cells of a chip component to
+    // the inputs inside the chip, see Circuit::flatten
+    NoRoute(ChipId),
+}
+
+impl fmt::Display for UnfoldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &UnfoldError::Cycle(ref chip_ids) => {
+                write!(f, "chips contain each other: {}", chip_ids.join(" -> "))
+            }
+            &UnfoldError::MissingChip(ref chip_id) => {
+                write!(f, "unknown chip {}", chip_id)
+            }
+            &UnfoldError::PinMismatch {
+                ref chip_id,
+                side,
+                descr_size,
+                input_size,
+            } => write!(
+                f,
+                "chip {} has {} cells on the {} side, but its input has {}",
+                chip_id,
+                descr_size,
+                format!("{:?}", side).to_lowercase(),
+                input_size
+            ),
+            &UnfoldError::NoRoute(ref chip_id) => {
+                write!(f, "no room to flatten chip {}", chip_id)
+            }
+        }
+    }
+}
+
+impl error::Error for UnfoldError {}
 
 pub type Point = ComponentId;
 
@@ -137,6 +221,13 @@ impl Circuit {
         }
     }
 
+    // Replace all references to a chip by another chip id
+    pub fn rename_chip(&mut self, old_id: &ChipId, new_id: &ChipId) {
+        for component in self.components.values_mut() {
+            component.element.rename_chip(old_id, new_id);
+        }
+    }
+
     pub fn components_in_rect(&self, rect: Rect) -> HashSet<ComponentId> {
         rect.iter()
             .map(|p| self.points.get(&p))
@@ -144,180 +235,36 @@ impl Circuit {
             .map(|p| *p.unwrap())
             .collect()
     }
+}
 
-    // Unfold this circuit by recursively instantiating chips.
-    // This mean that chip components are replaced by the circuit, as it
-    // is given in the chip database.
-    // Note that the points map of the resulting circuit is not valid.
-    // Returns None if the chips are cyclic.
-    // TODO: Unfolding might also be possible to do without creating an
-    // intermediate circuit, while creating the CompactGraph.
-    pub fn unfold(&self, chip_db: &ChipDb) -> Option<Circuit> {
-        let mut unfolded_circuit = self.clone();
-        let mut finished_ids = HashSet::new();
-
-        // Keep unfolding chips until there are no unfolded chips left.
-        // To prevent infinite loops, keep track of which chip is contained
-        // in which chips.
-        // TODO: This check might be better done in the editor.
-        let mut contained_in = HashMap::new();
-
-        loop {
-            let chip_component_ids = unfolded_circuit
-                .components
-                .iter()
-                .filter(|&(c_id, component)| match &component.element {
-                    &Element::Chip(_chip_id, ref _descr) => true,
-                    _ => false,
-                } &&
-                    !finished_ids.contains(c_id))
-                .map(|(&c_id, _component)| c_id)
-                .collect::<Vec<_>>();
-
-            if chip_component_ids.len() == 0 {
-                break;
-            }
-
-            for &chip_component_id in chip_component_ids.iter() {
-                finished_ids.insert(chip_component_id);
-
-                let chip_component = unfolded_circuit
-                    .components
-                    .get(&chip_component_id)
-                    .unwrap()
-                    .clone();
-                let chip_element_descr = chip_component.element.descr();
-
-                if let Element::Chip(chip_id, _descr) = chip_component.element {
-                    let chip = chip_db.get(&chip_id).unwrap();
-
-                    // Map from cell IDs inside chip circuit to cell IDs in
-                    // unfolded circuit
-                    let mut id_map = HashMap::new();
-
-                    for (&c_id, component) in chip.circuit.components.iter() {
-                        if c_id == chip.left_input_id {
-                            // The input components of a chip serve as the gluing points
-                            // to the outer circuit. Here, we can assume that the number
-                            // of cell edges to the left is the same for the chip component
-                            // as well as the left input inside the chip circuit.
-                            let left_cells = chip_element_descr
-                                .cells
-                                .iter()
-                                .enumerate()
-                                .filter(|&(_i, &(dir, _k))| dir == Dir::Left)
-                                .map(|(i, _)| i);
-
-                            for (inner_cell_index, cell_index) in
-                                left_cells.enumerate()
-                            {
-                                id_map.insert((c_id, inner_cell_index), (
-                                    chip_component_id,
-                                    cell_index,
-                                ));
-                            }
-                        } else if c_id == chip.right_input_id {
-                            let right_cells = chip_element_descr
-                                .cells
-                                .iter()
-                                .enumerate()
-                                .filter(|&(_i, &(dir, _k))| dir == Dir::Right)
-                                .map(|(i, _)| i);
-
-                            for (inner_cell_index, cell_index) in
-                                right_cells.enumerate()
-                            {
-                                id_map.insert((c_id, inner_cell_index), (
-                                    chip_component_id,
-                                    cell_index,
-                                ));
-                            }
-                        } else {
-                            // Add components of inner chip
-                            let new_id = unfolded_circuit.next_component_id;
-                            unfolded_circuit.next_component_id += 1;
-
-                            for cell_index in 0..component.cells.len() {
-                                let node = chip.circuit
-                                    .graph
-                                    .get_node((c_id, cell_index))
-                                    .unwrap();
-                                unfolded_circuit.graph.add_node(
-                                    (new_id, cell_index),
-                                    *node,
-                                );
-                                id_map.insert(
-                                    (c_id, cell_index),
-                                    (new_id, cell_index),
-                                );
-                            }
-
-                            unfolded_circuit.components.insert(
-                                new_id,
-                                component.clone(),
-                            );
-
-                            // If we just inserted a chip component, keep track of the origin,
-                            // to check for cycles
-                            if let &Element::Chip(inner_chip_id,
-                                                  ref _chip_descr) =
-                                &component.element
-                            {
-                                if contained_in.get(&inner_chip_id).is_none() {
-                                    contained_in.insert(
-                                        inner_chip_id,
-                                        HashSet::new(),
-                                    );
-                                }
-                                contained_in
-                                    .get_mut(&inner_chip_id)
-                                    .unwrap()
-                                    .insert(chip_id);
-
-                                // Take a transitive closure of the contained_in relation
-                                if contained_in.get(&chip_id).is_none() {
-                                    contained_in.insert(
-                                        chip_id,
-                                        HashSet::new(),
-                                    );
-                                }
-                                for &outer_chip_id in contained_in
-                                    .get(&chip_id)
-                                    .unwrap()
-                                    .clone()
-                                    .iter()
-                                {
-                                    contained_in
-                                        .get_mut(&inner_chip_id)
-                                        .unwrap()
-                                        .insert(outer_chip_id);
-                                    if outer_chip_id == inner_chip_id {
-                                        // Cycle
-                                        return None;
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    // Insert new edges for the unfolded circuit
-                    for (&(ref cell_id_a, ref cell_id_b), &edge) in
-                        chip.circuit.graph.edges().iter()
-                    {
-                        let new_cell_id_a = *id_map.get(cell_id_a).unwrap();
-                        let new_cell_id_b = *id_map.get(cell_id_b).unwrap();
-
-                        unfolded_circuit.graph.add_edge(
-                            new_cell_id_a,
-                            new_cell_id_b,
-                            edge,
-                        );
-                    }
-                } else {
-                    panic!("component should be a Chip");
-                }
-            }
+// The cells on each side of a chip component are glued to the cells of the
+// input component of that side inside the chip, so their numbers need to match
+fn check_pins(
+    chip_id: &ChipId,
+    descr: &ElementDescr,
+    chip: &Chip,
+) -> Result<(), UnfoldError> {
+    let sides = [
+        (Dir::Left, chip.left_input_id),
+        (Dir::Right, chip.right_input_id),
+    ];
+    for &(side, input_id) in sides.iter() {
+        let descr_size =
+            descr.cells.iter().filter(|&&(dir, _k)| dir == side).count();
+        let input_size = chip.circuit
+            .components
+            .get(&input_id)
+            .map_or(0, |input| input.cells.len());
+
+        if descr_size != input_size {
+            return Err(UnfoldError::PinMismatch {
+                chip_id: chip_id.clone(),
+                side: side,
+                descr_size: descr_size,
+                input_size: input_size,
+            });
         }
-        Some(unfolded_circuit)
     }
+
+    Ok(())
 }
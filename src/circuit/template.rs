@@ -0,0 +1,330 @@
+use std::collections::{BTreeSet, HashMap};
+use std::cmp;
+
+use types::Dir;
+
+use super::{CellId, ComponentId, ChipId, Chip, ChipDb, Circuit, Component,
+            Edge, Element, ElementDescr, Graph, InstancePath, Hierarchy,
+            UnfoldError};
+
+// Receives the components and edges of an unfolded circuit, see
+// Circuit::unfold_into. This makes it possible to build something else than
+// a Circuit from them, for example the state of a simulation.
+pub trait UnfoldTarget {
+    // Components are added in ascending order of their ids, each before the
+    // edges that attach to its cells
+    fn add_component(&mut self, id: ComponentId, component: &Component);
+
+    fn add_edge(&mut self, id_a: CellId, id_b: CellId, edge: &Edge);
+}
+
+// A cell of an unfolded chip
+#[derive(Clone, Copy, Debug)]
+enum TemplateCell {
+    // Cell of the component with the given index in the template
+    Component(usize, usize),
+
+    // Cell of the input component on the given side inside the chip. It is
+    // glued to the corresponding cell of the chip component.
+    Pin(Dir, usize),
+}
+
+// The circuit of a chip with all the chips inside it unfolded, ready to be
+// copied for each chip component. Templates are kept in the ChipDb until the
+// chip, or one of the chips it contains, is changed.
+pub struct Template {
+    // Components in the order in which they get their ids when the template
+    // is instantiated. This does not include the input components of the
+    // chip. Each component comes with the instance, relative to the chip, and
+    // the id in the circuit of that instance.
+    components: Vec<(InstancePath, ComponentId, Component)>,
+
+    edges: Vec<(TemplateCell, TemplateCell, Edge)>,
+
+    // Cells of the input components of the chip and of its nested chips,
+    // which are glued to other cells
+    glued: Vec<(InstancePath, CellId, TemplateCell)>,
+
+    // The chip itself and all the chips that are nested in it
+    chip_ids: BTreeSet<ChipId>,
+}
+
+impl Template {
+    // Unfold the circuit of a chip that is nested in the chips of
+    // `chip_path`. The templates of the nested chips are taken from the
+    // ChipDb.
+    pub fn new(
+        chip_id: &ChipId,
+        chip: &Chip,
+        chip_db: &ChipDb,
+        chip_path: &mut Vec<ChipId>,
+    ) -> Result<Template, UnfoldError> {
+        let circuit = &chip.circuit;
+
+        let mut ids = circuit
+            .components
+            .keys()
+            .filter(|&&id| {
+                id != chip.left_input_id && id != chip.right_input_id
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        let indices = ids.iter()
+            .enumerate()
+            .map(|(index, &id)| (id, index))
+            .collect::<HashMap<_, _>>();
+        let template_cell = |(id, cell_index): CellId| {
+            if id == chip.left_input_id {
+                TemplateCell::Pin(Dir::Left, cell_index)
+            } else if id == chip.right_input_id {
+                TemplateCell::Pin(Dir::Right, cell_index)
+            } else {
+                TemplateCell::Component(indices[&id], cell_index)
+            }
+        };
+
+        let mut template = Template {
+            components: ids.iter()
+                .map(|&id| (vec![], id, circuit.components[&id].clone()))
+                .collect(),
+            edges: circuit
+                .graph
+                .edges()
+                .iter()
+                .map(|(&(id_a, id_b), &edge)| {
+                    (template_cell(id_a), template_cell(id_b), edge)
+                })
+                .collect(),
+            glued: Vec::new(),
+            chip_ids: BTreeSet::new(),
+        };
+        template.chip_ids.insert(chip_id.clone());
+
+        for &input_id in [chip.left_input_id, chip.right_input_id].iter() {
+            for cell_index in 0..circuit.components[&input_id].cells.len() {
+                let cell_id = (input_id, cell_index);
+                template.glued.push((vec![], cell_id, template_cell(cell_id)));
+            }
+        }
+
+        // Nested chips are appended after the components of this chip, in
+        // the order of their chip components
+        for (index, &id) in ids.iter().enumerate() {
+            let element = &circuit.components[&id].element;
+            let inner_chip_id = match element {
+                &Element::Chip(ref inner_chip_id, _) => inner_chip_id,
+                _ => continue,
+            };
+
+            let descr = element.descr();
+            check_chip(inner_chip_id, &descr, chip_db)?;
+            let inner = chip_db.template(inner_chip_id, chip_path)?;
+
+            let first_index = template.components.len();
+            let map_cell = |cell: TemplateCell| match cell {
+                TemplateCell::Component(inner_index, cell_index) => {
+                    let index = first_index + inner_index;
+                    TemplateCell::Component(index, cell_index)
+                }
+                TemplateCell::Pin(side, k) => {
+                    TemplateCell::Component(index, pin_cell(&descr, side, k))
+                }
+            };
+            let prefixed = |path: &InstancePath| {
+                Some(id).into_iter().chain(path.iter().cloned()).collect()
+            };
+
+            template.components.extend(inner.components.iter().map(
+                |&(ref path, origin_id, ref component)| {
+                    (prefixed(path), origin_id, component.clone())
+                },
+            ));
+            template.edges.extend(inner.edges.iter().map(
+                |&(cell_a, cell_b, edge)| {
+                    (map_cell(cell_a), map_cell(cell_b), edge)
+                },
+            ));
+            template.glued.extend(inner.glued.iter().map(
+                |&(ref path, origin_cell_id, cell)| {
+                    (prefixed(path), origin_cell_id, map_cell(cell))
+                },
+            ));
+            template.chip_ids.extend(inner.chip_ids.iter().cloned());
+        }
+
+        Ok(template)
+    }
+
+    // Whether the template has to be made again when the given chip changes
+    pub fn depends_on(&self, chip_id: &ChipId) -> bool {
+        self.chip_ids.contains(chip_id)
+    }
+
+    // Copy the template for a chip component. The components get consecutive
+    // ids, starting at `first_id`.
+    fn instantiate<T: UnfoldTarget>(
+        &self,
+        chip_component_id: ComponentId,
+        descr: &ElementDescr,
+        first_id: ComponentId,
+        hierarchy: &mut Hierarchy,
+        target: &mut T,
+    ) {
+        let cell_id = |cell: TemplateCell| match cell {
+            TemplateCell::Component(index, cell_index) => {
+                (first_id + index, cell_index)
+            }
+            TemplateCell::Pin(side, k) => {
+                (chip_component_id, pin_cell(descr, side, k))
+            }
+        };
+        let prefixed = |path: &InstancePath| {
+            Some(chip_component_id)
+                .into_iter()
+                .chain(path.iter().cloned())
+                .collect::<Vec<_>>()
+        };
+
+        for (index, &(ref path, origin_id, ref component)) in
+            self.components.iter().enumerate()
+        {
+            let id = first_id + index;
+            hierarchy.add_component(
+                id,
+                component.cells.len(),
+                &prefixed(path),
+                origin_id,
+            );
+            target.add_component(id, component);
+        }
+
+        for &(cell_a, cell_b, ref edge) in self.edges.iter() {
+            target.add_edge(cell_id(cell_a), cell_id(cell_b), edge);
+        }
+
+        for &(ref path, origin_cell_id, cell) in self.glued.iter() {
+            hierarchy.glue(&prefixed(path), origin_cell_id, cell_id(cell));
+        }
+    }
+}
+
+impl Circuit {
+    // Unfold this circuit by recursively instantiating chips.
+    // This mean that chip components are replaced by the circuit, as it
+    // is given in the chip database.
+    // Note that the points map of the resulting circuit is not valid, since
+    // the components of chips keep their positions. Use flatten to get a
+    // circuit that can be shown and edited.
+    // Fails if the chips contain each other, or if they do not match the
+    // chip database, see UnfoldError. The Hierarchy tells which chip instance
+    // each cell of the unfolded circuit belongs to.
+    pub fn unfold(
+        &self,
+        chip_db: &ChipDb,
+    ) -> Result<(Circuit, Hierarchy), UnfoldError> {
+        let mut unfolded = UnfoldedCircuit(Circuit {
+            components: HashMap::new(),
+            graph: Graph::new(),
+            points: self.points.clone(),
+            next_component_id: self.next_component_id,
+        });
+        let hierarchy = self.unfold_into(chip_db, &mut unfolded)?;
+
+        Ok((unfolded.0, hierarchy))
+    }
+
+    // Unfold this circuit like unfold, but pass the components and edges of
+    // the result on to `target` instead of creating a circuit. Components are
+    // added in the same order, and with the same ids, as in the circuit that
+    // unfold returns.
+    pub fn unfold_into<T: UnfoldTarget>(
+        &self,
+        chip_db: &ChipDb,
+        target: &mut T,
+    ) -> Result<Hierarchy, UnfoldError> {
+        let mut hierarchy = Hierarchy::new();
+
+        let mut ids = self.components.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+
+        // The components of this circuit keep their ids
+        let mut instances = Vec::new();
+        for &id in ids.iter() {
+            let component = &self.components[&id];
+            hierarchy.add_component(id, component.cells.len(), &vec![], id);
+            target.add_component(id, component);
+
+            if let Element::Chip(ref chip_id, _) = component.element {
+                let descr = component.element.descr();
+                check_chip(chip_id, &descr, chip_db)?;
+                let template = chip_db.template(chip_id, &mut Vec::new())?;
+                instances.push((id, descr, template));
+            }
+        }
+
+        for (&(id_a, id_b), edge) in self.graph.edges().iter() {
+            target.add_edge(id_a, id_b, edge);
+        }
+
+        // The components of the chips are added after that, one chip
+        // component after the other
+        let mut next_id = self.next_component_id;
+        for (chip_component_id, descr, template) in instances {
+            template.instantiate(
+                chip_component_id,
+                &descr,
+                next_id,
+                &mut hierarchy,
+                target,
+            );
+            next_id += template.components.len();
+        }
+
+        Ok(hierarchy)
+    }
+}
+
+// Builds the circuit that Circuit::unfold returns
+struct UnfoldedCircuit(Circuit);
+
+impl UnfoldTarget for UnfoldedCircuit {
+    fn add_component(&mut self, id: ComponentId, component: &Component) {
+        let circuit = &mut self.0;
+
+        for (i, &pos) in component.cells.iter().enumerate() {
+            circuit.graph.add_node((id, i), pos);
+        }
+        circuit.components.insert(id, component.clone());
+        circuit.next_component_id = cmp::max(circuit.next_component_id, id + 1);
+    }
+
+    fn add_edge(&mut self, id_a: CellId, id_b: CellId, edge: &Edge) {
+        self.0.graph.add_edge(id_a, id_b, *edge);
+    }
+}
+
+// Make sure that a chip component can be replaced by the circuit of its chip
+fn check_chip(
+    chip_id: &ChipId,
+    descr: &ElementDescr,
+    chip_db: &ChipDb,
+) -> Result<(), UnfoldError> {
+    match chip_db.get(chip_id) {
+        Some(chip) => super::check_pins(chip_id, descr, chip),
+        None => Err(UnfoldError::MissingChip(chip_id.clone())),
+    }
+}
+
+// Index of the `k`-th cell on the given side of a chip component
+fn pin_cell(descr: &ElementDescr, side: Dir, k: usize) -> usize {
+    descr
+        .cells
+        .iter()
+        .enumerate()
+        .filter(|&(_i, &(dir, _k))| dir == side)
+        .nth(k)
+        .map(|(i, _)| i)
+        .unwrap()
+}
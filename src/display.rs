@@ -5,8 +5,10 @@ use ggez::graphics::{self, Drawable};
 
 use types::Dir;
 use camera::Camera;
-use circuit::{Circuit, SwitchType, Element, Component};
+use circuit::{Circuit, Component, Hierarchy, InstancePath, DEFAULT_RESISTANCE,
+              DEFAULT_PRESSURE};
 use flow;
+use kinds;
 
 pub const EDGE_LENGTH: f32 = 1.5;
 pub const HALF_EDGE_LENGTH: f32 = EDGE_LENGTH / 2.0;
@@ -30,7 +32,6 @@ impl DrawMode {
 
 pub struct Display {}
 
-// TODO: Clean up: significant overlap between draw_component and draw_flow
 impl Display {
     pub fn new() -> Display {
         Display {}
@@ -39,13 +40,14 @@ impl Display {
     pub fn draw_grid_edges(
         &self,
         ctx: &mut Context,
+        font: &graphics::Font,
         camera: &Camera,
         circuit: &Circuit,
         mode: DrawMode,
     ) -> GameResult<()> {
         graphics::set_color(ctx, mode.to_color())?;
 
-        for (&(cell_a, cell_b), &_edge) in circuit.graph().edges().iter() {
+        for (&(cell_a, cell_b), &edge) in circuit.graph().edges().iter() {
             let a = *circuit.graph().get_node(cell_a).unwrap();
             let b = *circuit.graph().get_node(cell_b).unwrap();
             let dir = Dir::from_coords(a, b);
@@ -60,6 +62,15 @@ impl Display {
             let b_p = graphics::Point::new(b_t.x, b_t.y);
 
             graphics::line(ctx, &vec![a_p, b_p])?;
+
+            // Only label edges whose resistance has been changed
+            if edge.resistance != DEFAULT_RESISTANCE {
+                let center_t = (a_t + b_t) / 2.0;
+                let text_str = format!("{}", edge.resistance);
+                let text = graphics::Text::new(ctx, &text_str, font)?;
+                let text_pos = graphics::Point::new(center_t.x, center_t.y);
+                text.draw(ctx, text_pos, 0.0)?;
+            }
         }
 
         Ok(())
@@ -104,250 +115,17 @@ impl Display {
     ) -> GameResult<()> {
         graphics::set_color(ctx, mode.to_color())?;
 
-        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
-
-        match &c.element {
-            &Element::Node => {
-                let r = graphics::Rect {
-                    x: p_t.x,
-                    y: p_t.y,
-                    w: camera.transform_distance(EDGE_LENGTH / 2.0),
-                    h: camera.transform_distance(EDGE_LENGTH / 2.0),
-                };
-
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
-            }
-            &Element::Bridge => {
-                let r = graphics::Rect {
-                    x: p_t.x,
-                    y: p_t.y,
-                    w: camera.transform_distance(EDGE_LENGTH / 2.0),
-                    h: camera.transform_distance(EDGE_LENGTH / 2.0),
-                };
-                let inner_r = graphics::Rect {
-                    x: p_t.x,
-                    y: p_t.y,
-                    w: camera.transform_distance(EDGE_LENGTH / 4.0),
-                    h: camera.transform_distance(EDGE_LENGTH / 4.0),
-                };
-
-                graphics::set_color(ctx, mode.to_color())?;
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
-                graphics::rectangle(ctx, graphics::DrawMode::Line, inner_r)?;
-
-                let left = Dir::Left.rotate_cw_n(c.rotation_cw);
-                let a = c.pos.cast() + left.delta().cast() * 0.25;
-                let b = a + left.invert().delta().cast() * 0.5;
-
-                let a_end = a + left.invert().delta().cast() * 0.125;
-                let b_end = b + left.delta().cast() * 0.125;
-
-                let a_t = camera.transform(a * EDGE_LENGTH);
-                let b_t = camera.transform(b * EDGE_LENGTH);
-                let a_end_t = camera.transform(a_end * EDGE_LENGTH);
-                let b_end_t = camera.transform(b_end * EDGE_LENGTH);
-
-                let a_p = graphics::Point::new(a_t.x, a_t.y);
-                let b_p = graphics::Point::new(b_t.x, b_t.y);
-                let a_end_p = graphics::Point::new(a_end_t.x, a_end_t.y);
-                let b_end_p = graphics::Point::new(b_end_t.x, b_end_t.y);
-
-                graphics::line(ctx, &vec![a_p, a_end_p])?;
-                graphics::line(ctx, &vec![b_p, b_end_p])?;
-            }
-            &Element::Switch(kind) => {
-                let left_dir = Dir::Left.rotate_cw_n(c.rotation_cw);
-                let flow_p = c.cells[1].cast();
-                let control_p = flow_p + left_dir.delta().cast() * 0.25;
-
-                let flow_p_t = camera.transform(flow_p * EDGE_LENGTH);
-                let control_p_t = camera.transform(control_p * EDGE_LENGTH);
-
-                graphics::circle(
-                    ctx,
-                    graphics::DrawMode::Fill,
-                    graphics::Point {
-                        x: control_p_t.x,
-                        y: control_p_t.y,
-                    },
-                    camera.transform_distance(HALF_EDGE_LENGTH * 0.3),
-                    50,
-                )?;
-
-                let r = graphics::Rect {
-                    x: flow_p_t.x,
-                    y: flow_p_t.y,
-                    w: camera.transform_distance(HALF_EDGE_LENGTH),
-                    h: camera.transform_distance(HALF_EDGE_LENGTH),
-                };
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
-
-                if kind == SwitchType::Off {
-                    graphics::set_color(
-                        ctx,
-                        graphics::Color::new(0.0, 0.0, 0.0, 1.0),
-                    )?;
-                    graphics::circle(
-                        ctx,
-                        graphics::DrawMode::Fill,
-                        graphics::Point {
-                            x: control_p_t.x,
-                            y: control_p_t.y,
-                        },
-                        camera.transform_distance(HALF_EDGE_LENGTH * 0.25),
-                        50,
-                    )?;
-                }
-            }
-            &Element::Source | &Element::Sink => {
-                let size = (c.size().cast() + Vector2::new(0.5, 0.5)) *
-                    EDGE_LENGTH;
-                let trans_size = camera.transform_delta(size);
-                let shift = c.size().cast() * HALF_EDGE_LENGTH;
-                let trans_shift = camera.transform_delta(shift);
-                let center = p_t + trans_shift;
-
-                //self.draw_component_edges(ctx, camera, c)?;
-
-                let r = graphics::Rect {
-                    x: center.x,
-                    y: center.y,
-                    w: trans_size.x,
-                    h: trans_size.y,
-                };
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
-
-                graphics::circle(
-                    ctx,
-                    graphics::DrawMode::Fill,
-                    graphics::Point {
-                        x: center.x,
-                        y: center.y,
-                    },
-                    camera.transform_distance(size.x / 2.0),
-                    50,
-                )?;
-
-                if c.element == Element::Sink {
-                    graphics::set_color(
-                        ctx,
-                        graphics::Color::new(0.0, 0.0, 0.0, 1.0),
-                    )?;
-                    graphics::circle(
-                        ctx,
-                        graphics::DrawMode::Fill,
-                        graphics::Point {
-                            x: center.x,
-                            y: center.y,
-                        },
-                        camera.transform_distance(size.x / 2.0 - 0.05),
-                        50,
-                    )?;
-                }
-            }
-            &Element::Input { size: _ } => {
-                let size = (c.size().cast() + Vector2::new(0.5, 0.5)) *
-                    EDGE_LENGTH;
-                let size_small = (c.size().cast() + Vector2::new(0.25, 0.25)) *
-                    EDGE_LENGTH;
-                let shift = c.size().cast() * HALF_EDGE_LENGTH;
-                let trans_size = camera.transform_delta(size);
-                let trans_size_small = camera.transform_delta(size_small);
-                let trans_shift = camera.transform_delta(shift);
-                let center = p_t + trans_shift;
-
-                let r = graphics::Rect {
-                    x: center.x,
-                    y: center.y,
-                    w: trans_size.x,
-                    h: trans_size.y,
-                };
-                graphics::rectangle(ctx, graphics::DrawMode::Fill, r)?;
-
-                let r_small = graphics::Rect {
-                    x: center.x,
-                    y: center.y,
-                    w: trans_size_small.x,
-                    h: trans_size_small.y,
-                };
-                graphics::set_color(
-                    ctx,
-                    graphics::Color::new(0.0, 0.0, 0.0, 1.0),
-                )?;
-                graphics::rectangle(ctx, graphics::DrawMode::Fill, r_small)?;
-            }
-            &Element::Output { size: _ } => {
-                let size = (c.size().cast() + Vector2::new(0.5, 0.5)) *
-                    EDGE_LENGTH;
-                let size_small = (c.size().cast() + Vector2::new(0.25, 0.25)) *
-                    EDGE_LENGTH;
-                let shift = c.size().cast() * HALF_EDGE_LENGTH;
-                let trans_size = camera.transform_delta(size);
-                let trans_size_small = camera.transform_delta(size_small);
-                let trans_shift = camera.transform_delta(shift);
-                let center = p_t + trans_shift;
-
-                let r = graphics::Rect {
-                    x: center.x,
-                    y: center.y,
-                    w: trans_size.x,
-                    h: trans_size.y,
-                };
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
-
-                let r_small = graphics::Rect {
-                    x: center.x,
-                    y: center.y,
-                    w: trans_size_small.x,
-                    h: trans_size_small.y,
-                };
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r_small)?;
-            }
-            &Element::Power => {
-                // Corner position of the nodes
-                let dir = Dir::Left.rotate_cw_n(c.rotation_cw);
-                let orth_dir = dir.rotate_cw();
-                let left = c.pos.cast() + dir.delta().cast() / 4.0;
-                let x = left + orth_dir.delta().cast() / 4.0;
-                let y = left + orth_dir.invert().delta().cast() / 4.0;
-                let z = c.pos.cast() + dir.invert().delta().cast() / 4.0;
-
-                let x_t = camera.transform(x * EDGE_LENGTH);
-                let y_t = camera.transform(y * EDGE_LENGTH);
-                let z_t = camera.transform(z * EDGE_LENGTH);
-
-                let vertices = vec![
-                    graphics::Point { x: x_t.x, y: x_t.y },
-                    graphics::Point { x: y_t.x, y: y_t.y },
-                    graphics::Point { x: z_t.x, y: z_t.y },
-                ];
-
-                graphics::polygon(ctx, graphics::DrawMode::Fill, &vertices)?;
-            }
-            &Element::Chip(ref chip_id, ref _chip_descr) => {
-                self.draw_component_edges(ctx, camera, c)?;
-
-                let size = (c.size().cast() + Vector2::new(0.5, 0.5)) *
-                    EDGE_LENGTH;
-                let trans_size = camera.transform_delta(size);
-                let shift = c.size().cast() * HALF_EDGE_LENGTH;
-                let trans_shift = camera.transform_delta(shift);
-                let center = p_t + trans_shift;
-
-                let r = graphics::Rect {
-                    x: center.x,
-                    y: center.y,
-                    w: trans_size.x,
-                    h: trans_size.y,
-                };
-
-                graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
-
-                let chip_str = format!("{:?}", chip_id);
-                let chip_text = graphics::Text::new(ctx, &chip_str, font)?;
-                let chip_text_pos = graphics::Point::new(center.x, center.y);
-                chip_text.draw(ctx, chip_text_pos, 0.0)?;
-            }
+        let kind = kinds::get(&c.element);
+        kind.draw(self, ctx, font, camera, c)?;
+
+        // Only label pressures that have been changed
+        let pressure = kind.pressure(&c.element).unwrap_or(DEFAULT_PRESSURE);
+        if pressure != DEFAULT_PRESSURE {
+            let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+            let text_str = format!("{}", pressure);
+            let text = graphics::Text::new(ctx, &text_str, font)?;
+            let text_pos = graphics::Point::new(p_t.x, p_t.y);
+            text.draw(ctx, text_pos, 0.0)?;
         }
 
         Ok(())
@@ -376,7 +154,7 @@ impl Display {
         circuit: &Circuit,
         mode: DrawMode,
     ) -> GameResult<()> {
-        self.draw_grid_edges(ctx, camera, circuit, mode)?;
+        self.draw_grid_edges(ctx, font, camera, circuit, mode)?;
         self.draw_components(ctx, font, camera, circuit, mode)
     }
 
@@ -387,96 +165,42 @@ impl Display {
         camera: &Camera,
         circuit: &Circuit,
         state: &flow::State,
+        hierarchy: &Hierarchy,
+        path: &InstancePath,
     ) -> GameResult<()> {
         for (&id, ref c) in circuit.components().iter() {
-            for (cell_index, _pos) in c.cells.iter().enumerate() {
-                if let Element::Input { .. } = c.element {
-                    continue;
-                }
-                if let Element::Output { .. } = c.element {
-                    continue;
-                }
+            let kind = kinds::get(&c.element);
 
-                let cell_id = (id, cell_index);
+            for (cell_index, _pos) in c.cells.iter().enumerate() {
+                let cell_id = match hierarchy.cell_id(path, (id, cell_index)) {
+                    Some(cell_id) => cell_id,
+                    None => continue,
+                };
                 let node_index = state.graph.node_index(cell_id);
                 let cell = state.flow.node(node_index);
-                let is_bridge_inner = c.element == Element::Bridge &&
-                    cell_index == 1;
 
-                let p = c.cells[cell_index].cast();
-                let p_t = camera.transform(p * EDGE_LENGTH);
-                let size = camera.transform_distance(EDGE_LENGTH * 0.45);
-                let size = if is_bridge_inner { size / 2.0 } else { size };
-                let size = if let &Element::Switch(_) = &c.element {
-                    size / 2.0
-                } else {
-                    size
-                };
-
-                let pressure = cell.pressure as f32;
+                let pressure =
+                    (cell.pressure / DEFAULT_PRESSURE).max(0.0).min(1.0) as f32;
                 graphics::set_color(
                     ctx,
                     graphics::Color::new(
-                        1.0 * (pressure / 100.0),
+                        1.0 * pressure,
                         0.0,
-                        1.0 * (1.0 - pressure / 100.0),
+                        1.0 * (1.0 - pressure),
                         1.0,
                     ),
                 )?;
 
-                if c.element == Element::Power {
-                    let dir = Dir::Left.rotate_cw_n(c.rotation_cw);
-                    let orth_dir = dir.rotate_cw();
-                    let left = c.pos.cast() + dir.delta().cast() / 4.0;
-                    let x = left + orth_dir.delta().cast() / 4.0;
-                    let y = left + orth_dir.invert().delta().cast() / 4.0;
-                    let z = c.pos.cast() + dir.invert().delta().cast() / 4.0;
-
-                    let x_t = camera.transform(x * EDGE_LENGTH);
-                    let y_t = camera.transform(y * EDGE_LENGTH);
-                    let z_t = camera.transform(z * EDGE_LENGTH);
-
-                    let vertices = vec![
-                        graphics::Point { x: x_t.x, y: x_t.y },
-                        graphics::Point { x: y_t.x, y: y_t.y },
-                        graphics::Point { x: z_t.x, y: z_t.y },
-                    ];
-
-                    graphics::polygon(
-                        ctx,
-                        graphics::DrawMode::Fill,
-                        &vertices,
-                    )?;
-                } else if c.element != Element::Source &&
-                           c.element != Element::Sink
-                {
-                    let r = graphics::Rect {
-                        x: p_t.x,
-                        y: p_t.y,
-                        w: size,
-                        h: size,
-                    };
-
-                    graphics::rectangle(ctx, graphics::DrawMode::Fill, r)?;
-
-                    if is_bridge_inner {
-                        self.draw_component(
-                            ctx,
-                            font,
-                            camera,
-                            c,
-                            DrawMode::Real,
-                        )?;
-                    }
-                } else {
-                    graphics::circle(
-                        ctx,
-                        graphics::DrawMode::Fill,
-                        graphics::Point { x: p_t.x, y: p_t.y },
-                        size / 2.0,
-                        50,
-                    )?;
-                }
+                kind.draw_cell(self, ctx, font, camera, c, cell_index, cell)?;
+            }
+
+            let components = &state.components;
+            let index = hierarchy.component_id(path, id).and_then(|id| {
+                components.binary_search_by_key(&id, |c| c.id).ok()
+            });
+            if let Some(index) = index {
+                let component = &components[index];
+                kind.draw_state(self, ctx, font, camera, c, component)?;
             }
         }
 
@@ -487,13 +211,22 @@ impl Display {
         for (&(cell_id_a, cell_id_b), &_edge) in
             circuit.graph().edges().iter()
         {
-            let node_index_a = state.graph.node_index(cell_id_a);
-            let node_index_b = state.graph.node_index(cell_id_b);
+            let flow_cell_ids = (
+                hierarchy.cell_id(path, cell_id_a),
+                hierarchy.cell_id(path, cell_id_b),
+            );
+            let (flow_cell_id_a, flow_cell_id_b) = match flow_cell_ids {
+                (Some(a), Some(b)) => (a, b),
+                _ => continue,
+            };
+            let node_index_a = state.graph.node_index(flow_cell_id_a);
+            let node_index_b = state.graph.node_index(flow_cell_id_b);
 
             let a_p = *circuit.graph().get_node(cell_id_a).unwrap();
             let b_p = *circuit.graph().get_node(cell_id_b).unwrap();
 
-            let edge_index = state.graph.edge_index(cell_id_a, cell_id_b);
+            let edge_index =
+                state.graph.edge_index(flow_cell_id_a, flow_cell_id_b);
             let edge = state.flow.edge(edge_index);
 
             /*if edge.enabled {
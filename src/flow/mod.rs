@@ -1,5 +1,9 @@
 mod state;
 mod simulate;
+mod solver;
 
-pub use self::state::{State, edge_quantity};
+pub use self::state::{State, Component, Cell, CellInit, CellRole,
+                      SimulationMode, Diagnostic, DiagnosticOrigins,
+                      edge_quantity};
 pub use self::simulate::time_step;
+pub use self::solver::{SolverParams, SolveError};
@@ -1,33 +1,38 @@
-use rulinalg;
-use rulinalg::matrix::{Matrix, BaseMatrix};
-use rulinalg::vector::Vector;
+use std::mem;
 
-use circuit::{Element, SwitchType};
-use flow::state::{State, edge_quantity};
+use circuit::ComponentId;
+use flow::state::{State, Component, SimulationMode, Diagnostic,
+                  edge_quantity};
+use flow::solver::{self, SparseMatrix, SolveError};
+use kinds;
 
 #[allow(non_snake_case)]
-fn solve_pressure(state: &mut State) -> Result<(), rulinalg::error::Error> {
+fn solve_pressure(state: &mut State) -> Result<(), SolveError> {
     let num_v = state.mut_idx_to_node_idx.len();
 
+    for node_idx in 0..state.graph.num_nodes() {
+        let node = state.flow.node_mut(node_idx);
+        if !node.bound_pressure && node.mut_idx.is_none() {
+            node.pressure = 0.0;
+        }
+    }
+
     if num_v == 0 {
         return Ok(());
     }
 
-    let mut A = Matrix::<f64>::zeros(num_v, num_v); // system
-    let mut b = Vector::<f64>::zeros(num_v); // rhs
-
-    for node_idx in 0..state.graph.num_nodes() {
-        if !state.flow.node(node_idx).bound_pressure {
-            state.flow.node_mut(node_idx).pressure = 0.0;
-        }
-    }
+    // Build the weighted graph Laplacian of the mutable cells. Each row
+    // corresponds to the condition that the sum of flow into a cell is zero.
+    // The pressure of bound neighbors is moved to the right hand side.
+    let mut A = SparseMatrix::new(); // system
+    let mut b = vec![0.0; num_v]; // rhs
 
-    // build rows
     for (mut_idx, &node_idx) in state.mut_idx_to_node_idx.iter().enumerate() {
         let row_id = mut_idx;
+        let mut diagonal = 0.0;
 
         // here the blobs have an impact, we add pressure on the rhs of each row
-        //b[row_id] -= state.flow.node(node_idx).load as f64;
+        //b[row_id] += state.flow.node(node_idx).load as f64;
 
         // step through neigbors -> either non-zero entry in matrix or add to rhs
         let neighbors = state.graph.neighbors(node_idx);
@@ -37,39 +42,33 @@ fn solve_pressure(state: &mut State) -> Result<(), rulinalg::error::Error> {
                 continue;
             }
 
+            // edges are weighted by their conductance
+            let conductance = 1.0 / edge.resistance;
+
             let neigh_node = state.flow.node(neigh_node_idx);
             if let Some(neigh_mut_idx) = neigh_node.mut_idx {
                 // mutable neighbor -> need to compute pressure
-                let col_id = neigh_mut_idx;
-
-                A[[row_id, col_id]] = 1.0;
+                A.push(neigh_mut_idx, -conductance);
             } else {
                 // immutable neighbor -> need to add to right side
-                b[row_id] -= neigh_node.pressure;
+                b[row_id] += conductance * neigh_node.pressure;
             }
 
-            // substract flow on rhs
-            //b[row_id] -= edge_quantity(node_idx, neigh_node_idx, edge.velocity);
-            A[[row_id, row_id]] -= 1.0;
+            diagonal += conductance;
         }
-    }
-
-    // output matrix for debug
-    //println!("A: {:?}", A);
 
-    // output rhs
-    //println!("b: {:?}", b);
-
-    // solve this linear system (vll leo sagt 'shit')
-    //let x = A.solve(b).unwrap();
+        A.push(row_id, diagonal);
+        A.finish_row();
+    }
 
-    let L = (-A).cholesky()?;
-    //println!("L: {:?}", L);
-    let y = L.solve_l_triangular((-b))?;
-    let x = L.transpose().solve_u_triangular(y)?;
+    // Use the pressures of the last step as initial guess
+    let mut x = state
+        .mut_idx_to_node_idx
+        .iter()
+        .map(|&node_idx| state.flow.node(node_idx).pressure)
+        .collect::<Vec<_>>();
 
-    // output pressures
-    //println!("{:?}", x);
+    solver::solve_cg(&A, &b, &mut x, &state.solver_params)?;
 
     // write pressures
     for (mut_idx, &node_idx) in state.mut_idx_to_node_idx.iter().enumerate() {
@@ -81,7 +80,16 @@ fn solve_pressure(state: &mut State) -> Result<(), rulinalg::error::Error> {
     Ok(())
 }
 
-fn project_velocities(state: &mut State) {
+fn project_velocities(state: &mut State, dt: f64) {
+    // Velocities approach the value given by the pressure difference
+    // exponentially. Integrating exactly over dt means that splitting a time
+    // step into smaller ones gives the same result.
+    let decay = if state.inertia > 0.0 {
+        (-dt / state.inertia).exp()
+    } else {
+        0.0
+    };
+
     // update velocities for all edges
     let edges = state.graph.edges().iter().enumerate();
     for (edge_idx, &(from_idx, to_idx)) in edges {
@@ -90,89 +98,64 @@ fn project_velocities(state: &mut State) {
 
         let edge = state.flow.edge_mut(edge_idx);
 
-        edge.old_velocity = edge.velocity;
-        edge.velocity = if edge.enabled {
-            press_from - press_to
+        let target_velocity = if edge.enabled {
+            (press_from - press_to) / edge.resistance
         } else {
             0.0
         };
+
+        edge.old_velocity = edge.velocity;
+        edge.velocity =
+            target_velocity + (edge.old_velocity - target_velocity) * decay;
     }
 }
 
-fn update_components(state: &mut State) {
-    for ref component in state.components.iter() {
-        match component.element {
-            Element::Switch(kind) => {
-                let threshold = 0.01;
-                let enabled = {
-                    let control_node_idx = component.cells[0];
-                    let control_cell = state.flow.node(control_node_idx);
-
-                    match kind {
-                        SwitchType::On => control_cell.in_flow > threshold,
-                        SwitchType::Off => control_cell.in_flow < threshold,
-                    }
-                };
-
-                let flow_node_idx = component.cells[1];
-                for &(_, edge_idx) in state.graph.neighbors(flow_node_idx) {
-                    let edge = state.flow.edge_mut(edge_idx);
-                    edge.enabled = enabled;
-                }
-            }
-            Element::Power => {
-                let threshold = 0.01;
-                let enabled = {
-                    let control_node_idx = component.cells[0];
-                    let control_cell = state.flow.node(control_node_idx);
-                    control_cell.in_flow > threshold
-                };
-
-                let power_cell_idx = component.cells[1];
-                let power_cell = state.flow.node_mut(power_cell_idx);
-                power_cell.bound_pressure = enabled;
-                power_cell.pressure = 100.0;
-            }
-            Element::Input { size } => {
-                for &cell_idx in component.cells.iter() {
-                    let enabled = state.flow.node(cell_idx).enabled;
-                    let input_cell = state.flow.node_mut(cell_idx);
-                    input_cell.bound_pressure = enabled;
-                    input_cell.pressure = 100.0;
-                }
-            }
-            _ => {}
-        }
+// Call f for each component in the order of State::components. The
+// components are taken out of the state meanwhile, so that f can change
+// both.
+fn for_each_component<F>(state: &mut State, mut f: F)
+where
+    F: FnMut(&mut Component, &mut State),
+{
+    let mut components = mem::replace(&mut state.components, Vec::new());
+
+    for component in components.iter_mut() {
+        f(component, state);
     }
+
+    state.components = components;
 }
 
-fn blob_flow(state: &mut State) {
-    /*println!("++++++++++++++++++++++++++++++++++++");
-    println!("START");
-    println!("++++++++++++++++++++++++++++++++++++");
-    for node_idx in 0 .. state.graph.num_nodes() {
-        println!("now cell {0} has {1}, mut_idx: {2:?}", 
-            node_idx, 
-            state.flow.node(node_idx).load,
-            state.flow.node(node_idx).mut_idx);
-    }
-    println!("++++++++++++++++++++++++++++++++++++");*/
+// Update the components that depend on the flow. Returns whether each switch,
+// gate and power component is enabled, in the order of State::components.
+fn update_components(state: &mut State) -> Vec<(ComponentId, bool)> {
+    let mut switches = Vec::new();
 
-    // set the source/sink loads to a default value (minor TODO: maybe this should be a global setting?)
-    for i in 0..state.source_cells.len() {
-        let cell = state.flow.node_mut(state.source_cells[i]);
-        cell.load = if cell.enabled { 100000.0 } else { 0.0 };
-    }
-    for i in 0..state.sink_cells.len() {
-        let cell = state.flow.node_mut(state.sink_cells[i]);
-        cell.load = 0.0;
-    }
+    for_each_component(state, |component, state| {
+        let kind = kinds::get(&component.element);
+        if let Some(enabled) = kind.update(component, state) {
+            switches.push((component.id, enabled));
+        }
+    });
+
+    switches
+}
 
-    // backup old loads we will override with accumulation of neighbors
+// Move load along the edges. Cells with bound pressure are the boundary of
+// the system: they supply whatever flows out of them, and absorb whatever
+// flows into them. All other cells only pass on the load they have, so the
+// load in these cells only changes by what is supplied and absorbed at the
+// boundary.
+fn transport_flow(state: &mut State, dt: f64) {
+    // Loads that end up in a boundary cell (for example because a switch
+    // bound its pressure) are absorbed right away
     for node_idx in 0..state.graph.num_nodes() {
         let cell = state.flow.node_mut(node_idx);
+        if cell.bound_pressure {
+            state.absorbed += cell.load;
+            cell.load = 0.0;
+        }
         cell.old_load = cell.load;
-        //cell.load = 0;
         cell.in_flow = 0.0;
         cell.out_flow = 0.0;
     }
@@ -181,26 +164,48 @@ fn blob_flow(state: &mut State) {
         state.flow.edge_mut(edge_idx).flow = 0.0;
     }
 
+    // The flows are the amounts that are moved, divided by dt
+    if dt == 0.0 {
+        return;
+    }
+
+    // Decide how much each cell gives to its neighbors. This only depends on
+    // the loads at the start of the tick, so the order in which the cells are
+    // visited does not matter.
     for node_idx in 0..state.graph.num_nodes() {
-        let cell_load = state.flow.node(node_idx).old_load;
+        let (bound_pressure, cell_load) = {
+            let cell = state.flow.node(node_idx);
+            (cell.bound_pressure, cell.old_load)
+        };
 
-        // first get sum of outflow to get relative flow
-        let mut out_flow_sum = 0.0;
-        let neighbors = state.graph.neighbors(node_idx);
-        for &(neigh_node_idx, edge_idx) in neighbors {
-            out_flow_sum += {
+        // At most velocity * dt can pass through an edge in one tick
+        let out_capacity = state
+            .graph
+            .neighbors(node_idx)
+            .iter()
+            .map(|&(neigh_node_idx, edge_idx)| {
                 let edge = state.flow.edge(edge_idx);
-                let edge_vel =
+                let velocity =
                     edge_quantity(node_idx, neigh_node_idx, edge.velocity);
-                if edge.enabled && edge_vel > 0.0 {
-                    edge_vel
+                if edge.enabled && velocity > 0.0 {
+                    velocity * dt
                 } else {
                     0.0
                 }
-            };
+            })
+            .sum::<f64>();
+        if out_capacity <= 0.0 {
+            continue;
         }
 
-        // distribute our load to neighbors respecting relative flow
+        // Cells can not give away more than they have, so if the edges could
+        // take more, the load is split in proportion to their capacity
+        let scale = if bound_pressure || cell_load >= out_capacity {
+            1.0
+        } else {
+            cell_load / out_capacity
+        };
+
         for &(neigh_node_idx, edge_idx) in state.graph.neighbors(node_idx) {
             let velocity = {
                 let edge = state.flow.edge(edge_idx);
@@ -214,41 +219,38 @@ fn blob_flow(state: &mut State) {
                 continue;
             }
 
-            let rel_vel = velocity / out_flow_sum;
-
-            // TODO: for now, accept that some load is lost in rounding
-            let flow = (rel_vel * cell_load).min(velocity);
-
-            /*println!("cell {0} is giving {1}: {2}% of {3}: {4}",
-                node_idx, neigh_node_idx, rel_vel * 100.0, cell_load, flow);*/
+            let amount = velocity * dt * scale;
 
-            {
-                let neigh_node = state.flow.node_mut(neigh_node_idx);
-                neigh_node.load += flow;
-                neigh_node.in_flow += flow;
-            }
-            {
-                let node = state.flow.node_mut(node_idx);
-                node.load -= flow;
-                node.out_flow += flow;
+            if bound_pressure {
+                state.supplied += amount;
+            } else {
+                state.flow.node_mut(node_idx).load -= amount;
             }
+            state.flow.node_mut(neigh_node_idx).load += amount;
 
+            state.flow.node_mut(node_idx).out_flow += amount / dt;
+            state.flow.node_mut(neigh_node_idx).in_flow += amount / dt;
             state.flow.edge_mut(edge_idx).flow +=
-                edge_quantity(node_idx, neigh_node_idx, flow);
+                edge_quantity(node_idx, neigh_node_idx, amount / dt);
         }
     }
 
-    /*println!("++++++++++++++++++++++++++++++++++++");
-    println!("END");
-    println!("++++++++++++++++++++++++++++++++++++");
-    for node_idx in 0 .. state.graph.num_nodes() {
-        println!("now cell {0} has {1}, mut_idx: {2:?}",
-            node_idx,
-            state.flow.node(node_idx).load,
-            state.flow.node(node_idx).mut_idx);
-
+    for node_idx in 0..state.graph.num_nodes() {
+        let cell = state.flow.node_mut(node_idx);
+        if cell.bound_pressure {
+            state.absorbed += cell.load;
+            cell.load = 0.0;
+        }
     }
-    println!("++++++++++++++++++++++++++++++++++++");*/
+}
+
+// Update the components that store what flowed into them, see
+// kinds::ElementKind::update_after_flow
+fn update_after_flow(state: &mut State, dt: f64) {
+    for_each_component(state, |component, state| {
+        let kind = kinds::get(&component.element);
+        kind.update_after_flow(component, state, dt);
+    });
 }
 
 fn instant_flow(state: &mut State) {
@@ -289,14 +291,313 @@ fn instant_flow(state: &mut State) {
     }
 }
 
-pub fn time_step(state: &mut State, _dt: f64) {
-    update_components(state);
+// Set the in_flow of each cell to the flow that the current pressure would
+// cause without inertia. This is used for evaluating the switches again
+// within a tick, without moving anything.
+fn probe_in_flow(state: &mut State) {
+    for node_idx in 0..state.graph.num_nodes() {
+        state.flow.node_mut(node_idx).in_flow = 0.0;
+    }
+
+    for (edge_idx, &(from_idx, to_idx)) in
+        state.graph.edges().iter().enumerate()
+    {
+        let edge = *state.flow.edge(edge_idx);
+        if !edge.enabled {
+            continue;
+        }
+
+        let press_from = state.flow.node(from_idx).pressure;
+        let press_to = state.flow.node(to_idx).pressure;
+        let velocity = (press_from - press_to) / edge.resistance;
+
+        if velocity > 0.0 {
+            state.flow.node_mut(to_idx).in_flow += velocity;
+        } else if velocity < 0.0 {
+            state.flow.node_mut(from_idx).in_flow -= velocity;
+        }
+    }
+}
+
+// Update the components that depend on the pressure, such as valves.
+// Returns the components that were changed.
+fn update_valves(state: &mut State) -> Vec<ComponentId> {
+    let mut changed = Vec::new();
+
+    for_each_component(state, |component, state| {
+        let kind = kinds::get(&component.element);
+        if kind.update_after_solve(component, state) {
+            changed.push(component.id);
+        }
+    });
+
+    changed
+}
+
+// Solve the pressure, and open and close the valves accordingly, until the
+// valves agree with the pressure. Since the valves make the system
+// non-linear, this may need several solves, starting with the valves as they
+// were in the previous tick.
+fn solve_with_valves(state: &mut State) -> Result<(), SolveError> {
+    let num_internal_edges = state
+        .components
+        .iter()
+        .map(|component| {
+            let kind = kinds::get(&component.element);
+            kind.internal_edges(&component.element).len()
+        })
+        .sum::<usize>();
+
+    let mut changed = Vec::new();
+
+    for _ in 0..2 * num_internal_edges + 1 {
+        // Only the diagnostics of the last solve are kept
+        state.diagnostics.clear();
+        state.update_mut_indices();
+        solve_pressure(state)?;
+
+        changed = update_valves(state);
+        if changed.is_empty() {
+            return Ok(());
+        }
+    }
+
+    // The valves that were changed last are left as they are, so the
+    // pressure has to be solved once more
+    state.diagnostics.clear();
     state.update_mut_indices();
-    if let Err(err) = solve_pressure(state) {
-        println!("Can't solve pressure: {:?}", err);
-        return;
+    solve_pressure(state)?;
+    state.diagnostics.push(Diagnostic::Oscillation(changed));
+
+    Ok(())
+}
+
+fn update_and_solve(
+    state: &mut State,
+) -> Result<Vec<(ComponentId, bool)>, SolveError> {
+    let switches = update_components(state);
+    solve_with_valves(state)?;
+    Ok(switches)
+}
+
+// Evaluate the switches and solve the pressure until the switches reach a
+// fixed point, so that a chain of switches reacts within a single tick. If
+// there is no fixed point after max_iterations evaluations, the components
+// that kept changing in the second half of the iterations are reported.
+fn settle(state: &mut State, max_iterations: usize) -> Result<(), SolveError> {
+    let mut switches = update_and_solve(state)?;
+    let mut changed = vec![false; switches.len()];
+
+    for iteration in 1..max_iterations {
+        probe_in_flow(state);
+        let new_switches = update_and_solve(state)?;
+
+        if new_switches == switches {
+            return Ok(());
+        }
+
+        if iteration >= max_iterations / 2 {
+            for (i, (&(_, old), &(_, new))) in
+                switches.iter().zip(new_switches.iter()).enumerate()
+            {
+                changed[i] = changed[i] || old != new;
+            }
+        }
+
+        switches = new_switches;
+    }
+
+    let component_ids = switches
+        .iter()
+        .zip(changed.iter())
+        .filter(|&(_, &changed)| changed)
+        .map(|(&(id, _), _)| id)
+        .collect();
+    state.diagnostics.push(Diagnostic::Oscillation(component_ids));
+
+    Ok(())
+}
+
+// Advance the simulation by dt seconds. With dt = 0, the pressure is solved
+// and the components are updated, but nothing moves.
+pub fn time_step(state: &mut State, dt: f64) {
+    assert!(dt >= 0.0, "invalid time step: {}", dt);
+
+    let result = match state.settle_iterations {
+        Some(max_iterations) => settle(state, max_iterations),
+        None => update_and_solve(state).map(|_| ()),
+    };
+
+    match result {
+        Ok(()) => {
+            project_velocities(state, dt);
+
+            match state.mode {
+                SimulationMode::Instant => instant_flow(state),
+                SimulationMode::Transport => transport_flow(state, dt),
+            }
+
+            update_after_flow(state, dt);
+        }
+        Err(err) => {
+            state.diagnostics.push(Diagnostic::SolveFailed(err));
+        }
+    }
+
+    // Skipped time steps are counted as well, so that clocks keep their pace
+    state.tick += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::Circuit;
+    use flow::SolverParams;
+
+    // A source and a sink, connected through two nodes with unequal
+    // resistances
+    const CIRCUIT: &'static str = "flow circuit 4
+component 0 0 0 0 source 100
+component 1 1 0 0 node
+component 2 2 0 0 node
+component 3 3 0 0 sink
+edge 0 0 1 0 1
+edge 1 0 2 0 2
+edge 2 0 3 0 0.5
+end
+";
+
+    // The same, with a reservoir in the middle
+    const RESERVOIR_CIRCUIT: &'static str = "flow circuit 4
+component 0 0 0 0 source 100
+component 1 1 0 0 node
+component 2 2 0 0 reservoir 5
+component 3 3 0 0 node
+component 4 4 0 0 sink
+edge 0 0 1 0 1
+edge 1 0 2 0 2
+edge 2 0 3 0 0.5
+edge 3 0 4 0 1
+end
+";
+
+    // Two switches that control each other. The flow through the `off`
+    // switch turns on the `on` switch, whose flow turns off the `off`
+    // switch, so there is no fixed point.
+    const FEEDBACK_CIRCUIT: &'static str = "flow circuit 5
+component 0 0 -1 0 source 100
+component 1 0 0 0 switch off 0.01 0.01
+component 2 1 0 0 node
+component 3 2 0 0 node
+component 4 3 0 0 switch on 0.01 0.01
+component 5 3 -1 0 source 100
+component 6 3 1 0 node
+component 7 2 1 0 node
+component 8 1 1 0 node
+component 9 0 1 0 node
+component 10 -1 1 0 node
+component 11 -1 0 0 node
+edge 0 0 1 1 1
+edge 1 1 2 0 1
+edge 2 0 3 0 1
+edge 3 0 4 0 1
+edge 5 0 4 1 1
+edge 4 1 6 0 1
+edge 6 0 7 0 1
+edge 7 0 8 0 1
+edge 8 0 9 0 1
+edge 9 0 10 0 1
+edge 10 0 11 0 1
+edge 11 0 1 0 1
+end
+";
+
+    // Run a circuit in SimulationMode::Transport and check that the load and
+    // fill held by the circuit only change by what is supplied and absorbed
+    fn check_conservation(circuit: &str) {
+        let circuit = Circuit::load(circuit.as_bytes()).unwrap();
+        let mut state = State::from_circuit(&circuit);
+        state.mode = SimulationMode::Transport;
+        state.inertia = 0.3;
+
+        let mut held = 0.0;
+        for _ in 0..200 {
+            time_step(&mut state, 0.1);
+            assert!(state.diagnostics.is_empty());
+
+            held = (0..state.graph.num_nodes())
+                .map(|node_idx| {
+                    let cell = state.flow.node(node_idx);
+                    cell.load + cell.fill
+                })
+                .sum::<f64>();
+            let error = state.supplied - state.absorbed - held;
+            assert!(error.abs() < 1e-9, "error {}", error);
+        }
+
+        assert!(state.supplied > 0.0 && held > 0.0);
+    }
+
+    #[test]
+    fn transport_conserves() {
+        check_conservation(CIRCUIT);
+        check_conservation(RESERVOIR_CIRCUIT);
+    }
+
+    #[test]
+    fn zero_time_step() {
+        let circuit = Circuit::load(CIRCUIT.as_bytes()).unwrap();
+        let mut state = State::from_circuit(&circuit);
+        state.mode = SimulationMode::Transport;
+
+        time_step(&mut state, 0.1);
+        let loads = (0..state.graph.num_nodes())
+            .map(|node_idx| state.flow.node(node_idx).load)
+            .collect::<Vec<_>>();
+        time_step(&mut state, 0.0);
+
+        for node_idx in 0..state.graph.num_nodes() {
+            let cell = state.flow.node(node_idx);
+            assert_eq!(cell.load, loads[node_idx]);
+            assert_eq!(cell.in_flow, 0.0);
+        }
+    }
+
+    #[test]
+    fn settle_oscillation() {
+        let circuit = Circuit::load(FEEDBACK_CIRCUIT.as_bytes()).unwrap();
+        let mut state = State::from_circuit(&circuit);
+        state.settle_iterations = Some(10);
+
+        time_step(&mut state, 0.1);
+
+        assert_eq!(state.diagnostics.len(), 1);
+        match state.diagnostics[0] {
+            Diagnostic::Oscillation(ref component_ids) => {
+                assert_eq!(component_ids, &vec![1, 4]);
+            }
+            ref other => panic!("expected Oscillation, got {}", other),
+        }
+        assert_eq!(state.tick, 1);
+    }
+
+    #[test]
+    fn solve_failed() {
+        let circuit = Circuit::load(CIRCUIT.as_bytes()).unwrap();
+        let mut state = State::from_circuit(&circuit);
+        state.solver_params = SolverParams {
+            tolerance: 1e-10,
+            max_iterations_per_row: 0,
+            min_max_iterations: 0,
+        };
+
+        time_step(&mut state, 0.1);
+
+        assert_eq!(state.diagnostics.len(), 1);
+        match state.diagnostics[0] {
+            Diagnostic::SolveFailed(SolveError::NotConverged { .. }) => {}
+            ref other => panic!("expected SolveFailed, got {}", other),
+        }
+        assert_eq!(state.tick, 1);
     }
-    project_velocities(state);
-    //blob_flow(state);
-    instant_flow(state);
 }
@@ -0,0 +1,293 @@
+// Sparse linear systems for the pressure computation.
+//
+// The pressure system is the graph Laplacian restricted to the cells whose
+// pressure is not bound. It is symmetric positive definite as long as every
+// group of connected mutable cells touches at least one bound cell, which
+// State::update_mut_indices makes sure of. This means that it can be solved
+// with the conjugate gradient method. Each row only has one entry
+// per neighbor of the cell, so the matrix is stored in compressed sparse row
+// format.
+
+// Square matrix in compressed sparse row format
+pub struct SparseMatrix {
+    // Index into cols and values where each row starts. Has one more element
+    // than there are rows, so that the entries of row i are in
+    // row_start[i]..row_start[i+1].
+    row_start: Vec<usize>,
+
+    cols: Vec<usize>,
+    values: Vec<f64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SolverParams {
+    // Stop when the residual norm is smaller than this, relative to the norm
+    // of the right hand side
+    pub tolerance: f64,
+
+    // Maximal number of iterations per dimension of the system
+    pub max_iterations_per_row: usize,
+
+    // Lower bound for the number of iterations, for small systems
+    pub min_max_iterations: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SolveError {
+    NotConverged { iterations: usize, residual: f64 },
+}
+
+impl Default for SolverParams {
+    fn default() -> SolverParams {
+        SolverParams {
+            tolerance: 1e-10,
+            max_iterations_per_row: 10,
+            min_max_iterations: 100,
+        }
+    }
+}
+
+impl SparseMatrix {
+    pub fn new() -> SparseMatrix {
+        SparseMatrix {
+            row_start: vec![0],
+            cols: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    // Add an entry to the row that is currently being built. Each column may
+    // only be given once per row.
+    pub fn push(&mut self, col: usize, value: f64) {
+        self.cols.push(col);
+        self.values.push(value);
+    }
+
+    // Finish the row that is currently being built and start the next one
+    pub fn finish_row(&mut self) {
+        self.row_start.push(self.cols.len());
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.row_start.len() - 1
+    }
+
+    pub fn row(&self, i: usize) -> (&[usize], &[f64]) {
+        let range = self.row_start[i]..self.row_start[i + 1];
+        (&self.cols[range.clone()], &self.values[range])
+    }
+
+    pub fn diagonal(&self) -> Vec<f64> {
+        (0..self.num_rows())
+            .map(|i| {
+                let (cols, values) = self.row(i);
+                cols.iter()
+                    .zip(values.iter())
+                    .find(|&(&col, _)| col == i)
+                    .map_or(0.0, |(_, &value)| value)
+            })
+            .collect()
+    }
+
+    // y = A * x
+    pub fn mul_vec(&self, x: &[f64], y: &mut [f64]) {
+        for i in 0..self.num_rows() {
+            let (cols, values) = self.row(i);
+            y[i] = cols.iter()
+                .zip(values.iter())
+                .map(|(&col, &value)| value * x[col])
+                .sum();
+        }
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+// Solve A * x = b for a symmetric positive definite matrix A with the
+// conjugate gradient method, using the diagonal of A as preconditioner.
+// The given x is used as the initial guess. Returns the number of iterations.
+pub fn solve_cg(
+    a: &SparseMatrix,
+    b: &[f64],
+    x: &mut [f64],
+    params: &SolverParams,
+) -> Result<usize, SolveError> {
+    let n = a.num_rows();
+    assert!(b.len() == n && x.len() == n);
+
+    let b_norm = dot(b, b).sqrt();
+    if b_norm == 0.0 {
+        for x_i in x.iter_mut() {
+            *x_i = 0.0;
+        }
+        return Ok(0);
+    }
+
+    let inv_diag = a.diagonal()
+        .iter()
+        .map(|&d| if d != 0.0 { 1.0 / d } else { 1.0 })
+        .collect::<Vec<_>>();
+
+    // r = b - A * x
+    let mut r = vec![0.0; n];
+    a.mul_vec(x, &mut r);
+    for i in 0..n {
+        r[i] = b[i] - r[i];
+    }
+
+    let mut z = (0..n).map(|i| inv_diag[i] * r[i]).collect::<Vec<_>>();
+    let mut p = z.clone();
+    let mut a_p = vec![0.0; n];
+    let mut r_z = dot(&r, &z);
+
+    let max_iterations = (params.max_iterations_per_row * n)
+        .max(params.min_max_iterations);
+    let threshold = params.tolerance * b_norm;
+
+    for iteration in 0..max_iterations {
+        let r_norm = dot(&r, &r).sqrt();
+        if r_norm <= threshold {
+            return Ok(iteration);
+        }
+
+        a.mul_vec(&p, &mut a_p);
+        let alpha = r_z / dot(&p, &a_p);
+
+        for i in 0..n {
+            x[i] += alpha * p[i];
+            r[i] -= alpha * a_p[i];
+            z[i] = inv_diag[i] * r[i];
+        }
+
+        let new_r_z = dot(&r, &z);
+        let beta = new_r_z / r_z;
+        r_z = new_r_z;
+
+        for i in 0..n {
+            p[i] = z[i] + beta * p[i];
+        }
+    }
+
+    let residual = dot(&r, &r).sqrt() / b_norm;
+    if residual <= params.tolerance {
+        Ok(max_iterations)
+    } else {
+        Err(SolveError::NotConverged {
+            iterations: max_iterations,
+            residual: residual,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Solve A * x = b by Gaussian elimination with partial pivoting
+    fn solve_dense(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Vec<f64> {
+        let n = b.len();
+
+        for k in 0..n {
+            let pivot = (k..n)
+                .max_by(|&i, &j| {
+                    a[i][k].abs().partial_cmp(&a[j][k].abs()).unwrap()
+                })
+                .unwrap();
+            a.swap(k, pivot);
+            b.swap(k, pivot);
+
+            for i in k + 1..n {
+                let factor = a[i][k] / a[k][k];
+                for j in k..n {
+                    a[i][j] -= factor * a[k][j];
+                }
+                b[i] -= factor * b[k];
+            }
+        }
+
+        let mut x = vec![0.0; n];
+        for k in (0..n).rev() {
+            let sum = (k + 1..n).map(|j| a[k][j] * x[j]).sum::<f64>();
+            x[k] = (b[k] - sum) / a[k][k];
+        }
+        x
+    }
+
+    // The Laplacian of a small graph with unequal resistances, restricted to
+    // the mutable cells 0..4. Cell 4 is bound to pressure 100, and cell 5 to
+    // pressure 0.
+    fn laplacian() -> (SparseMatrix, Vec<Vec<f64>>, Vec<f64>) {
+        let n = 4;
+        let pressures = [100.0, 0.0];
+        let edges = [
+            (4, 0, 1.0),
+            (0, 1, 2.0),
+            (1, 2, 0.5),
+            (0, 2, 8.0),
+            (2, 3, 4.0),
+            (1, 3, 0.25),
+            (3, 5, 3.0),
+        ];
+
+        let mut dense = vec![vec![0.0; n]; n];
+        let mut b = vec![0.0; n];
+        for &(i, j, resistance) in edges.iter() {
+            let conductance = 1.0 / resistance;
+            for &(row, col) in [(i, j), (j, i)].iter() {
+                if row >= n {
+                    continue;
+                }
+                dense[row][row] += conductance;
+                if col < n {
+                    dense[row][col] -= conductance;
+                } else {
+                    b[row] += conductance * pressures[col - n];
+                }
+            }
+        }
+
+        let mut a = SparseMatrix::new();
+        for row in 0..n {
+            for col in 0..n {
+                if dense[row][col] != 0.0 {
+                    a.push(col, dense[row][col]);
+                }
+            }
+            a.finish_row();
+        }
+
+        (a, dense, b)
+    }
+
+    #[test]
+    fn cg_matches_direct_solve() {
+        let (a, dense, b) = laplacian();
+        let expected = solve_dense(dense, b.clone());
+
+        let mut x = vec![0.0; b.len()];
+        solve_cg(&a, &b, &mut x, &SolverParams::default()).unwrap();
+
+        for (x_i, expected_i) in x.iter().zip(expected.iter()) {
+            let error = (x_i - expected_i).abs();
+            assert!(error < 1e-8, "{} != {}", x_i, expected_i);
+        }
+    }
+
+    #[test]
+    fn cg_not_converged() {
+        let (a, _, b) = laplacian();
+        let params = SolverParams {
+            tolerance: 1e-10,
+            max_iterations_per_row: 0,
+            min_max_iterations: 1,
+        };
+
+        let mut x = vec![0.0; b.len()];
+        match solve_cg(&a, &b, &mut x, &params) {
+            Err(SolveError::NotConverged { iterations: 1, .. }) => {}
+            result => panic!("expected NotConverged, got {:?}", result),
+        }
+    }
+}
@@ -1,7 +1,13 @@
+use std::fmt;
+use std::mem;
 use std::ops::Neg;
 
-use circuit::{self, ComponentId, Element, Circuit, CellId};
+use canon_map::Canonize;
+use circuit::{self, ComponentId, Element, Circuit, CellId, ChipDb, Hierarchy,
+              UnfoldError, UnfoldTarget};
 use graph::{NodeIndex, CompactGraph, CompactGraphState};
+use flow::solver::{SolverParams, SolveError};
+use kinds;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Edge {
@@ -11,9 +17,12 @@ pub struct Edge {
 
     // Velocity from the smaller node index to the larger node index
     pub velocity: f64,
+
+    // Velocity before the last time step
     pub old_velocity: f64,
 
-    // Flow in the last tick from the smaller node index to the larger node index
+    // Average flow during the last tick from the smaller node index to the
+    // larger node index
     pub flow: f64,
 }
 
@@ -25,20 +34,187 @@ pub struct Cell {
     // Pressure of cell, recomputed every step
     pub pressure: f64,
 
-    // Blobs moving through the graph
+    // Blobs moving through the graph. The load is the amount of fluid in the
+    // cell, which is only tracked in SimulationMode::Transport. The flows are
+    // the average flow into and out of the cell during the last tick.
     pub enabled: bool,
     pub load: f64,
     pub old_load: f64,
     pub in_flow: f64,
     pub out_flow: f64,
 
+    // Amount of fluid stored in a reservoir. This is kept in both simulation
+    // modes, and it is not part of the load.
+    pub fill: f64,
+
     // Index in the matrix for pressure solving
     pub mut_idx: Option<usize>,
 }
 
+// How a cell takes part in the simulation, see
+// kinds::ElementKind::init_cell
+#[derive(Clone, Copy, Debug)]
+pub struct CellInit {
+    // Pressure that the cell is bound to at the start, if any
+    pub pressure: Option<f64>,
+
+    pub role: CellRole,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CellRole {
+    Inner,
+    Source,
+    Sink,
+
+    // Cells of a level input or output with the given number of cells. The
+    // cell index is the index of the input or output.
+    Input(usize),
+    Output(usize),
+}
+
+// How fluid is moved through the circuit in each time step
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SimulationMode {
+    // The flow follows the edge velocities without delay, and the cells
+    // don't store anything. This is enough to tell whether flow is present.
+    Instant,
+
+    // Fluid is moved from cell to cell, never faster than the edge velocities
+    // allow. The amount of fluid is conserved: it only enters at cells with
+    // bound pressure, and leaves at cells with bound pressure.
+    Transport,
+}
+
+// Problems that were noticed in the last time step
+#[derive(Clone, Debug)]
+pub enum Diagnostic {
+    // Cells that are connected to each other, but not to any cell with bound
+    // pressure. Their pressure is undetermined, so it is set to zero.
+    FloatingIsland(Vec<CellId>),
+
+    // The pressure could not be computed, so the time step was skipped
+    SolveFailed(SolveError),
+
+    // The switches, gates and power components did not reach a fixed point
+    // within the allowed number of settle iterations, or the valves could not
+    // be opened and closed consistently with the pressure. Contains the
+    // components that kept changing.
+    Oscillation(Vec<ComponentId>),
+}
+
+impl Diagnostic {
+    // Show the diagnostic with the cells and components of the circuit that
+    // was unfolded, instead of those of the unfolded circuit. These are
+    // written with the instance path in front, for example `3/5.0` for cell
+    // 0 of component 5 in the chip that is component 3 of the circuit.
+    pub fn with_origins<'a>(
+        &'a self,
+        hierarchy: &'a Hierarchy,
+    ) -> DiagnosticOrigins<'a> {
+        DiagnosticOrigins {
+            diagnostic: self,
+            hierarchy: hierarchy,
+        }
+    }
+
+    fn write(
+        &self,
+        f: &mut fmt::Formatter,
+        hierarchy: Option<&Hierarchy>,
+    ) -> fmt::Result {
+        match self {
+            &Diagnostic::FloatingIsland(ref cell_ids) => {
+                write!(f, "floating island:")?;
+                for &cell_id in cell_ids.iter() {
+                    write!(f, " ")?;
+                    write_cell(f, cell_id, hierarchy)?;
+                }
+                Ok(())
+            }
+            &Diagnostic::SolveFailed(ref err) => {
+                write!(f, "can't solve pressure: {:?}", err)
+            }
+            &Diagnostic::Oscillation(ref component_ids) => {
+                write!(f, "oscillation:")?;
+                for &component_id in component_ids.iter() {
+                    write!(f, " ")?;
+                    write_component(f, component_id, hierarchy)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+// See Diagnostic::with_origins
+pub struct DiagnosticOrigins<'a> {
+    diagnostic: &'a Diagnostic,
+    hierarchy: &'a Hierarchy,
+}
+
+impl<'a> fmt::Display for DiagnosticOrigins<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.diagnostic.write(f, Some(self.hierarchy))
+    }
+}
+
+fn write_path(
+    f: &mut fmt::Formatter,
+    path: &circuit::InstancePath,
+) -> fmt::Result {
+    for id in path.iter() {
+        write!(f, "{}/", id)?;
+    }
+    Ok(())
+}
+
+fn write_cell(
+    f: &mut fmt::Formatter,
+    cell_id: CellId,
+    hierarchy: Option<&Hierarchy>,
+) -> fmt::Result {
+    match hierarchy.and_then(|hierarchy| hierarchy.origin(cell_id)) {
+        Some(&(ref path, (id, cell_index))) => {
+            write_path(f, path)?;
+            write!(f, "{}.{}", id, cell_index)
+        }
+        None => write!(f, "{}.{}", cell_id.0, cell_id.1),
+    }
+}
+
+fn write_component(
+    f: &mut fmt::Formatter,
+    id: ComponentId,
+    hierarchy: Option<&Hierarchy>,
+) -> fmt::Result {
+    match hierarchy.and_then(|hierarchy| hierarchy.origin((id, 0))) {
+        Some(&(ref path, (origin_id, _))) => {
+            write_path(f, path)?;
+            write!(f, "{}", origin_id)
+        }
+        None => write!(f, "{}", id),
+    }
+}
+
 pub struct Component {
+    pub id: ComponentId,
     pub element: Element,
     pub cells: Vec<NodeIndex>,
+
+    // Whether the control cell of a switch or power component is active, see
+    // circuit::Threshold. For a gate, whether its flow cell is enabled, and
+    // for a toggle, whether it is on.
+    pub active: bool,
+
+    // Whether each control cell of a gate is active, indexed by cell
+    pub controls_active: Vec<bool>,
 }
 
 pub struct State {
@@ -50,6 +226,31 @@ pub struct State {
     pub sink_cells: Vec<NodeIndex>,
     pub input_cells: Vec<NodeIndex>,
     pub output_cells: Vec<NodeIndex>,
+    pub solver_params: SolverParams,
+    pub diagnostics: Vec<Diagnostic>,
+
+    // Number of time steps that have been simulated so far. Clocks and
+    // patterns are switched depending on this.
+    pub tick: usize,
+
+    // Time constant in seconds with which edge velocities follow changes in
+    // pressure. With zero inertia, velocities follow the pressure instantly.
+    pub inertia: f64,
+
+    pub mode: SimulationMode,
+
+    // If set, switches, gates and power components are evaluated again after
+    // solving the pressure, until they do not change anymore or the given
+    // number of evaluations (at least two) is reached. Otherwise, they are
+    // only evaluated once per tick, based on the flow of the previous tick.
+    pub settle_iterations: Option<usize>,
+
+    // Total amount of fluid that has entered and left the circuit through
+    // cells with bound pressure in SimulationMode::Transport. The loads of
+    // all other cells and the fill of the reservoirs add up to the
+    // difference.
+    pub supplied: f64,
+    pub absorbed: f64,
 }
 
 pub fn edge_quantity<T: Neg<Output = T>>(
@@ -61,35 +262,85 @@ pub fn edge_quantity<T: Neg<Output = T>>(
 }
 
 impl State {
-    fn new_component(
-        graph: &CompactGraph<CellId>,
-        id: ComponentId,
-        component: &circuit::Component,
-    ) -> Component {
-        let cells = (0..component.cells.len())
-            .map(|cell_index| {
-                let cell_id = (id, cell_index);
-                graph.node_index(cell_id)
-            })
-            .collect();
+    // Pass a click on the given component on to its kind, see
+    // kinds::ElementKind::click. The change shows in the flow with the next
+    // time step.
+    pub fn click_component(&mut self, id: ComponentId) {
+        let index = match self.components.binary_search_by_key(&id, |c| c.id) {
+            Ok(index) => index,
+            Err(_) => return,
+        };
 
-        Component {
-            element: component.element.clone(),
-            cells: cells,
+        let mut components = mem::replace(&mut self.components, Vec::new());
+        {
+            let component = &mut components[index];
+            kinds::get(&component.element).click(component, self);
         }
+        self.components = components;
     }
 
+    // Determine which cells need their pressure to be computed, and assign
+    // their index in the pressure system. Cells whose pressure is not bound
+    // are grouped into islands that are connected by enabled edges. Only
+    // islands that are connected to a cell with bound pressure can be solved,
+    // the other islands are reported as diagnostics, and their pressure is
+    // left at zero.
     pub fn update_mut_indices(&mut self) {
-        let mut mut_idx_to_node_idx = Vec::new();
+        let num_nodes = self.graph.num_nodes();
 
-        for node_idx in 0..self.graph.num_nodes() {
-            let is_mut = {
+        let is_candidate = (0..num_nodes)
+            .map(|node_idx| {
                 let node = self.flow.node(node_idx);
                 let any_neighbor = self.graph.neighbors(node_idx).iter().any(
                     |&(_, edge_idx)| self.flow.edge(edge_idx).enabled,
                 );
                 !node.bound_pressure && any_neighbor
-            };
+            })
+            .collect::<Vec<_>>();
+
+        // Find islands with a depth-first search
+        let mut island = vec![None; num_nodes];
+        let mut island_anchored = Vec::new();
+        let mut island_cells = Vec::new();
+
+        for start_idx in 0..num_nodes {
+            if !is_candidate[start_idx] || island[start_idx].is_some() {
+                continue;
+            }
+
+            let island_idx = island_anchored.len();
+            let mut anchored = false;
+            let mut cells = Vec::new();
+            let mut stack = vec![start_idx];
+            island[start_idx] = Some(island_idx);
+
+            while let Some(node_idx) = stack.pop() {
+                cells.push(node_idx);
+
+                for &(neigh_idx, edge_idx) in self.graph.neighbors(node_idx) {
+                    if !self.flow.edge(edge_idx).enabled {
+                        continue;
+                    }
+
+                    if is_candidate[neigh_idx] {
+                        if island[neigh_idx].is_none() {
+                            island[neigh_idx] = Some(island_idx);
+                            stack.push(neigh_idx);
+                        }
+                    } else if self.flow.node(neigh_idx).bound_pressure {
+                        anchored = true;
+                    }
+                }
+            }
+
+            island_anchored.push(anchored);
+            island_cells.push(cells);
+        }
+
+        let mut mut_idx_to_node_idx = Vec::new();
+
+        for node_idx in 0..num_nodes {
+            let is_mut = island[node_idx].map_or(false, |i| island_anchored[i]);
 
             self.flow.node_mut(node_idx).mut_idx = if is_mut {
                 mut_idx_to_node_idx.push(node_idx);
@@ -100,102 +351,397 @@ impl State {
         }
 
         self.mut_idx_to_node_idx = mut_idx_to_node_idx;
+
+        for (cells, anchored) in island_cells.into_iter().zip(island_anchored) {
+            if !anchored {
+                let mut cell_ids = cells
+                    .into_iter()
+                    .map(|node_idx| self.graph.node_id(node_idx))
+                    .collect::<Vec<_>>();
+                cell_ids.sort();
+
+                self.diagnostics.push(Diagnostic::FloatingIsland(cell_ids));
+            }
+        }
     }
 
+    // The state for simulating a circuit as it is, without unfolding chips
     pub fn from_circuit(circuit: &Circuit) -> State {
-        let mut node_idx_counter = 0;
-        let mut source_cells = Vec::new();
-        let mut sink_cells = Vec::new();
-        let mut input_cells = Vec::new();
-        let mut output_cells = Vec::new();
-        let flow = CompactGraphState::new(
-            &circuit.graph(),
-            |(component_id, cell_index), _node| {
-                let component =
-                    circuit.components().get(&component_id).unwrap();
-
-                let pressure = match &component.element {
-                    &Element::Node => None,
-                    &Element::Bridge => None,
-                    &Element::Source => {
-                        source_cells.push(node_idx_counter);
-                        Some(100.0)
-                    }
-                    &Element::Sink => {
-                        sink_cells.push(node_idx_counter);
-                        Some(0.0)
-                    }
-                    &Element::Switch(_kind) => {
-                        if cell_index == 0 {
-                            sink_cells.push(node_idx_counter);
-                            Some(0.0)
-                        } else {
-                            None
-                        }
-                    }
-                    &Element::Input { size } => {
-                        source_cells.push(node_idx_counter);
-                        input_cells.resize(size, 0);
-                        input_cells[cell_index] = node_idx_counter;
-                        Some(100.0)
-                    }
-                    &Element::Output { size } => {
-                        sink_cells.push(node_idx_counter);
-                        output_cells.resize(size, 0);
-                        output_cells[cell_index] = node_idx_counter;
-                        Some(0.0)
-                    }
-                    &Element::Power => {
-                        if cell_index == 0 { Some(0.0) } else { None }
-                    }
-                    &Element::Chip(..) => None,
-                };
-
-                node_idx_counter += 1;
-
-                let bound_pressure = pressure.is_some();
-                let init_pressure = pressure.unwrap_or(0.0);
-
-                Cell {
-                    bound_pressure: bound_pressure,
-                    pressure: init_pressure,
-                    enabled: true,
-                    load: 0.0,
-                    old_load: 0.0,
-                    in_flow: 0.0,
-                    out_flow: 0.0,
-                    mut_idx: None,
-                }
-            },
-            |_, _, _| {
-                Edge {
-                    resistance: 0.0,
-                    enabled: true,
-                    velocity: 0.0,
-                    old_velocity: 0.0,
-                    flow: 0.0,
-                }
-            },
-        );
+        let mut builder = StateBuilder::new();
 
-        let graph = CompactGraph::new(&circuit.graph());
-        let components = circuit
-            .components()
-            .iter()
-            .map(|(&id, component)| {
-                State::new_component(&graph, id, component)
-            })
-            .collect();
+        // Components are kept in ascending order of their ids, so that they
+        // are updated in the same order every time
+        let mut component_ids =
+            circuit.components().keys().cloned().collect::<Vec<_>>();
+        component_ids.sort();
+        for id in component_ids {
+            builder.add_component(id, &circuit.components()[&id]);
+        }
+
+        for (&(id_a, id_b), edge) in circuit.graph().edges().iter() {
+            builder.add_edge(id_a, id_b, edge);
+        }
+
+        builder.finish()
+    }
+
+    // The state for simulating a circuit with its chips unfolded. This gives
+    // the same state as from_circuit for the result of Circuit::unfold, but
+    // without creating the unfolded circuit.
+    pub fn unfold(
+        circuit: &Circuit,
+        chip_db: &ChipDb,
+    ) -> Result<(State, Hierarchy), UnfoldError> {
+        let mut builder = StateBuilder::new();
+        let hierarchy = circuit.unfold_into(chip_db, &mut builder)?;
+
+        Ok((builder.finish(), hierarchy))
+    }
+}
+
+// Collects the cells, edges and components of the state. Since components are
+// added in ascending order of their ids, their cells are added in the order of
+// the NodeIndex that CompactGraph gives them.
+struct StateBuilder {
+    cell_ids: Vec<CellId>,
+    cells: Vec<Cell>,
+
+    // Edges with their canonical cell id pair, in the order they were added
+    edges: Vec<((CellId, CellId), Edge)>,
+
+    components: Vec<Component>,
+    source_cells: Vec<NodeIndex>,
+    sink_cells: Vec<NodeIndex>,
+    input_cells: Vec<NodeIndex>,
+    output_cells: Vec<NodeIndex>,
+}
+
+impl StateBuilder {
+    fn new() -> StateBuilder {
+        StateBuilder {
+            cell_ids: Vec::new(),
+            cells: Vec::new(),
+            edges: Vec::new(),
+            components: Vec::new(),
+            source_cells: Vec::new(),
+            sink_cells: Vec::new(),
+            input_cells: Vec::new(),
+            output_cells: Vec::new(),
+        }
+    }
+
+    fn finish(self) -> State {
+        // The edges within components come first, so they replace edges of
+        // the circuit between the same cells
+        let mut edges = self.edges;
+        edges.sort_by_key(|&(ids, _edge)| ids);
+        edges.dedup_by_key(|&mut (ids, _edge)| ids);
+
+        let (edge_ids, edges): (Vec<_>, Vec<_>) = edges.into_iter().unzip();
+        let graph = CompactGraph::from_sorted_ids(self.cell_ids, edge_ids);
+        let flow = CompactGraphState::from_vecs(self.cells, edges);
 
         State {
             graph: graph,
             flow: flow,
-            components: components,
+            components: self.components,
             mut_idx_to_node_idx: Vec::new(),
-            source_cells: source_cells,
-            sink_cells: sink_cells,
-            input_cells: input_cells,
-            output_cells: output_cells,
+            source_cells: self.source_cells,
+            sink_cells: self.sink_cells,
+            input_cells: self.input_cells,
+            output_cells: self.output_cells,
+            solver_params: SolverParams::default(),
+            diagnostics: Vec::new(),
+            tick: 0,
+            inertia: 0.0,
+            mode: SimulationMode::Instant,
+            settle_iterations: None,
+            supplied: 0.0,
+            absorbed: 0.0,
+        }
+    }
+}
+
+impl UnfoldTarget for StateBuilder {
+    fn add_component(
+        &mut self,
+        id: ComponentId,
+        component: &circuit::Component,
+    ) {
+        let element = &component.element;
+        let kind = kinds::get(element);
+        let mut cells = Vec::new();
+
+        for cell_index in 0..component.cells.len() {
+            let node_idx = self.cells.len();
+            let init = kind.init_cell(element, cell_index);
+
+            match init.role {
+                CellRole::Inner => {}
+                CellRole::Source => self.source_cells.push(node_idx),
+                CellRole::Sink => self.sink_cells.push(node_idx),
+                CellRole::Input(size) => {
+                    self.source_cells.push(node_idx);
+                    self.input_cells.resize(size, 0);
+                    self.input_cells[cell_index] = node_idx;
+                }
+                CellRole::Output(size) => {
+                    self.sink_cells.push(node_idx);
+                    self.output_cells.resize(size, 0);
+                    self.output_cells[cell_index] = node_idx;
+                }
+            }
+
+            self.cell_ids.push((id, cell_index));
+            self.cells.push(Cell {
+                bound_pressure: init.pressure.is_some(),
+                pressure: init.pressure.unwrap_or(0.0),
+                enabled: true,
+                load: 0.0,
+                old_load: 0.0,
+                in_flow: 0.0,
+                out_flow: 0.0,
+                fill: 0.0,
+                mut_idx: None,
+            });
+            cells.push(node_idx);
+        }
+
+        // In addition to the edges of the circuit, the flow goes through the
+        // edges within components, see kinds::ElementKind::internal_edges
+        for (cell_a, cell_b) in kind.internal_edges(element) {
+            let edge = circuit::Edge::default();
+            self.add_edge((id, cell_a), (id, cell_b), &edge);
+        }
+
+        self.components.push(Component {
+            id: id,
+            element: element.clone(),
+            cells: cells,
+            active: kind.init_active(element),
+            controls_active: vec![false; component.cells.len()],
+        });
+    }
+
+    fn add_edge(&mut self, id_a: CellId, id_b: CellId, edge: &circuit::Edge) {
+        self.edges.push((
+            (id_a, id_b).canonize(),
+            Edge {
+                resistance: edge.resistance,
+                enabled: true,
+                velocity: 0.0,
+                old_velocity: 0.0,
+                flow: 0.0,
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::{Action, ChipDescr, Coords};
+    use flow;
+
+    fn place(
+        circuit: &mut Circuit,
+        element: Element,
+        x: isize,
+    ) -> ComponentId {
+        let component = element.new_component(Coords::new(x, 0), 0);
+        let action = Action::PlaceComponent(component);
+        assert!(action.can_perform(circuit));
+        action.perform(circuit);
+        circuit.get_last_component_id().unwrap()
+    }
+
+    fn connect(circuit: &mut Circuit, id_a: CellId, id_b: CellId) {
+        let action = Action::PlaceEdge(id_a, id_b, circuit::Edge::default());
+        assert!(action.can_perform(circuit));
+        action.perform(circuit);
+    }
+
+    // Connect the inputs of a chip through a node, or through a chip
+    // component if `chip_id` is given
+    fn fill_chip(chip_db: &mut ChipDb, id: &str, chip_id: Option<&str>) {
+        let (left_input_id, right_input_id) = {
+            let chip = chip_db.get(&id.to_string()).unwrap();
+            (chip.left_input_id, chip.right_input_id)
+        };
+        let (element, right_cell) = match chip_id {
+            Some(chip_id) => {
+                let chip_id = chip_id.to_string();
+                let descr = chip_db.get_descr(&chip_id).unwrap().clone();
+                (Element::Chip(chip_id, descr), 1)
+            }
+            None => (Element::Node, 0),
+        };
+
+        let circuit = chip_db.get_circuit_mut(&id.to_string()).unwrap();
+        let id = place(circuit, element, 2);
+        connect(circuit, (left_input_id, 0), (id, 0));
+        connect(circuit, (id, right_cell), (right_input_id, 0));
+    }
+
+    fn assert_same_state(a: &State, b: &State) {
+        assert_eq!(a.graph.num_nodes(), b.graph.num_nodes());
+        for node_idx in 0..a.graph.num_nodes() {
+            assert_eq!(a.graph.node_id(node_idx), b.graph.node_id(node_idx));
+            let cell_a = a.flow.node(node_idx);
+            let cell_b = b.flow.node(node_idx);
+            assert_eq!(cell_a.bound_pressure, cell_b.bound_pressure);
+            assert_eq!(cell_a.pressure, cell_b.pressure);
+        }
+
+        assert_eq!(a.graph.edges(), b.graph.edges());
+        for edge_idx in 0..a.graph.num_edges() {
+            let resistance_a = a.flow.edge(edge_idx).resistance;
+            assert_eq!(resistance_a, b.flow.edge(edge_idx).resistance);
+        }
+
+        assert_eq!(a.components.len(), b.components.len());
+        for (component_a, component_b) in
+            a.components.iter().zip(b.components.iter())
+        {
+            assert_eq!(component_a.id, component_b.id);
+            assert_eq!(component_a.element, component_b.element);
+            assert_eq!(component_a.cells, component_b.cells);
         }
+
+        assert_eq!(a.source_cells, b.source_cells);
+        assert_eq!(a.sink_cells, b.sink_cells);
+    }
+
+    // Unfold a circuit both through Circuit::unfold and directly into the
+    // state, and check that the results agree
+    fn unfold_both(circuit: &Circuit, chip_db: &ChipDb) -> State {
+        let (unfolded, _) = circuit.unfold(chip_db).unwrap();
+        let expected = State::from_circuit(&unfolded);
+        let (state, _) = State::unfold(circuit, chip_db).unwrap();
+        assert_same_state(&state, &expected);
+        state
+    }
+
+    #[test]
+    fn unfold_nested_chips() {
+        let descr = ChipDescr {
+            inner_size: Coords::new(6, 2),
+            left_size: 1,
+            right_size: 1,
+        };
+        let mut chip_db = ChipDb::new("chips");
+        chip_db.add("inner".to_string(), descr.clone()).unwrap();
+        chip_db.add("outer".to_string(), descr.clone()).unwrap();
+        fill_chip(&mut chip_db, "inner", None);
+        fill_chip(&mut chip_db, "outer", Some("inner"));
+
+        let mut circuit = Circuit::new();
+        let source = Element::Source {
+            pressure: circuit::DEFAULT_PRESSURE,
+        };
+        let chip = Element::Chip("outer".to_string(), descr.clone());
+        let source_id = place(&mut circuit, source, 0);
+        let chip_id = place(&mut circuit, chip, 2);
+        let sink_id = place(&mut circuit, Element::Sink, 20);
+        connect(&mut circuit, (source_id, 0), (chip_id, 0));
+        connect(&mut circuit, (chip_id, 1), (sink_id, 0));
+
+        let state = unfold_both(&circuit, &chip_db);
+
+        // Changing the inner chip has to make its template again, also for
+        // the outer chip that contains it
+        {
+            let inner = chip_db.get_circuit_mut(&"inner".to_string()).unwrap();
+            place(inner, Element::Node, 4);
+        }
+        let changed_state = unfold_both(&circuit, &chip_db);
+        assert_eq!(
+            changed_state.graph.num_nodes(),
+            state.graph.num_nodes() + 1
+        );
+    }
+
+    #[test]
+    fn floating_island() {
+        let mut circuit = Circuit::new();
+        let source = Element::Source {
+            pressure: circuit::DEFAULT_PRESSURE,
+        };
+        let source_id = place(&mut circuit, source, 0);
+        let node_id = place(&mut circuit, Element::Node, 1);
+        let sink_id = place(&mut circuit, Element::Sink, 2);
+        connect(&mut circuit, (source_id, 0), (node_id, 0));
+        connect(&mut circuit, (node_id, 0), (sink_id, 0));
+
+        // Two nodes that are only connected to each other
+        let node_a = place(&mut circuit, Element::Node, 4);
+        let node_b = place(&mut circuit, Element::Node, 5);
+        connect(&mut circuit, (node_a, 0), (node_b, 0));
+
+        let mut state = State::from_circuit(&circuit);
+        flow::time_step(&mut state, 0.1);
+
+        assert_eq!(state.diagnostics.len(), 1);
+        match state.diagnostics[0] {
+            Diagnostic::FloatingIsland(ref cell_ids) => {
+                assert_eq!(cell_ids, &vec![(node_a, 0), (node_b, 0)]);
+            }
+            ref diagnostic => panic!("unexpected {}", diagnostic),
+        }
+
+        // The rest of the circuit is still solved
+        let island_idx = state.graph.node_index((node_a, 0));
+        assert_eq!(state.flow.node(island_idx).pressure, 0.0);
+        let node_idx = state.graph.node_index((node_id, 0));
+        assert!(state.flow.node(node_idx).pressure > 0.0);
+        let sink_idx = state.graph.node_index((sink_id, 0));
+        assert!(state.flow.node(sink_idx).in_flow > 0.0);
+    }
+
+    #[test]
+    fn diagnostic_origins() {
+        let descr = ChipDescr {
+            inner_size: Coords::new(6, 2),
+            left_size: 1,
+            right_size: 1,
+        };
+        let mut chip_db = ChipDb::new("chips");
+        chip_db.add("chip".to_string(), descr.clone()).unwrap();
+        fill_chip(&mut chip_db, "chip", None);
+
+        // Two nodes that are only connected to each other
+        let (node_a, node_b) = {
+            let inner = chip_db.get_circuit_mut(&"chip".to_string()).unwrap();
+            let node_a = place(inner, Element::Node, 4);
+            let node_b = place(inner, Element::Node, 5);
+            connect(inner, (node_a, 0), (node_b, 0));
+            (node_a, node_b)
+        };
+
+        let mut circuit = Circuit::new();
+        let source = Element::Source {
+            pressure: circuit::DEFAULT_PRESSURE,
+        };
+        let chip = Element::Chip("chip".to_string(), descr.clone());
+        let source_id = place(&mut circuit, source, 0);
+        let chip_id = place(&mut circuit, chip, 2);
+        let sink_id = place(&mut circuit, Element::Sink, 20);
+        connect(&mut circuit, (source_id, 0), (chip_id, 0));
+        connect(&mut circuit, (chip_id, 1), (sink_id, 0));
+
+        let (mut state, hierarchy) = State::unfold(&circuit, &chip_db).unwrap();
+        state.update_mut_indices();
+
+        assert_eq!(state.diagnostics.len(), 1);
+        assert_eq!(
+            state.diagnostics[0].with_origins(&hierarchy).to_string(),
+            format!(
+                "floating island: {}/{}.0 {}/{}.0",
+                chip_id,
+                node_a,
+                chip_id,
+                node_b
+            )
+        );
     }
 }
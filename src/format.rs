@@ -0,0 +1,201 @@
+// Helpers for the line-based text files that circuits, chips and levels are
+// stored in.
+//
+// Every file starts with a header line of the form
+//
+//     flow <kind> <version>
+//
+// followed by one entry per line. An entry is a keyword and a list of
+// arguments, separated by whitespace. Empty lines and lines starting with `#`
+// are ignored.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+// Version written by the current code. Files with a smaller version can still
+// be loaded, the loaders fill in defaults for fields that did not exist yet.
+//
+// Version history:
+//     1: Initial version
+//     2: Edges have a resistance
+//     3: Switches and power components have a threshold
+//     4: Sources, inputs and power components have a pressure
+//     5: gate, valve, toggle, reservoir, clock and pattern elements
+pub const VERSION: u32 = 5;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+
+    // A line could not be parsed
+    Syntax { line: usize, msg: String },
+
+    // The file was written by a newer version, or is of a different kind
+    Header { line: usize, msg: String },
+
+    // The file is well-formed, but its contents are inconsistent
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Io(ref err) => write!(f, "{}", err),
+            &Error::Syntax { line, ref msg } => {
+                write!(f, "line {}: {}", line, msg)
+            }
+            &Error::Header { line, ref msg } => {
+                write!(f, "line {}: {}", line, msg)
+            }
+            &Error::Invalid(ref msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+// A single non-empty line, split into tokens
+pub struct Line {
+    pub number: usize,
+    tokens: Vec<String>,
+    next: usize,
+}
+
+impl Line {
+    pub fn keyword(&self) -> &str {
+        &self.tokens[0]
+    }
+
+    pub fn error(&self, msg: String) -> Error {
+        Error::Syntax {
+            line: self.number,
+            msg: msg,
+        }
+    }
+
+    pub fn next_str(&mut self, what: &str) -> Result<&str> {
+        if self.next < self.tokens.len() {
+            self.next += 1;
+            Ok(&self.tokens[self.next - 1])
+        } else {
+            Err(self.error(format!("expected {}", what)))
+        }
+    }
+
+    pub fn next<T: FromStr>(&mut self, what: &str) -> Result<T> {
+        let number = self.number;
+        let token = self.next_str(what)?.to_string();
+
+        token.parse().map_err(|_| {
+            Error::Syntax {
+                line: number,
+                msg: format!("invalid {}: `{}`", what, token),
+            }
+        })
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.next < self.tokens.len()
+    }
+
+    // Make sure that all tokens have been consumed
+    pub fn finish(&self) -> Result<()> {
+        if self.has_next() {
+            Err(self.error(format!(
+                "unexpected `{}`",
+                self.tokens[self.next]
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct Reader<R: BufRead> {
+    lines: io::Lines<R>,
+    number: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(read: R) -> Reader<R> {
+        Reader {
+            lines: read.lines(),
+            number: 0,
+        }
+    }
+
+    // Returns the next line that is not empty or a comment
+    pub fn next_line(&mut self) -> Result<Option<Line>> {
+        while let Some(line) = self.lines.next() {
+            let line = line?;
+            self.number += 1;
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let tokens = line.split_whitespace()
+                .map(|token| token.to_string())
+                .collect();
+            // The first token is the keyword, so arguments start at 1
+            return Ok(Some(Line {
+                number: self.number,
+                tokens: tokens,
+                next: 1,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    // Like next_line, but treats the end of the file as an error
+    pub fn expect_line(&mut self, what: &str) -> Result<Line> {
+        match self.next_line()? {
+            Some(line) => Ok(line),
+            None => Err(Error::Syntax {
+                line: self.number,
+                msg: format!("unexpected end of file, expected {}", what),
+            }),
+        }
+    }
+
+    // Reads the header line and returns the version of the file
+    pub fn header(&mut self, kind: &str) -> Result<u32> {
+        let mut line = self.expect_line("header")?;
+        let number = line.number;
+
+        if line.keyword() != "flow" || line.next_str("file kind")? != kind {
+            return Err(Error::Header {
+                line: number,
+                msg: format!("expected `flow {} <version>`", kind),
+            });
+        }
+
+        let version = line.next("version")?;
+        line.finish()?;
+
+        if version == 0 || version > VERSION {
+            return Err(Error::Header {
+                line: number,
+                msg: format!(
+                    "unsupported version {} (supported: 1 to {})",
+                    version,
+                    VERSION
+                ),
+            });
+        }
+
+        Ok(version)
+    }
+}
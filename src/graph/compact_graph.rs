@@ -14,12 +14,19 @@ pub type EdgeIndex = usize;
 // be used for lookups in the Vecs. Note that CompactGraph is only about the
 // structure of the graph and does not store any additional information for the
 // nodes and edges.
+//
+// Nodes are indexed in ascending order of their NodeId, and edges in ascending
+// order of their canonical NodeId pair, so that the same Graph always gives
+// the same indices.
 pub struct CompactGraph<NodeId: Copy + Ord + Hash> {
     // Map from NodeIds to NodeIndex
     indices: Graph<NodeId, NodeIndex, EdgeIndex>,
 
-    // List of neighbors (and the corresponding edge) for each node. Nodes are
-    // indexed by NodeIndex.
+    // NodeId of each node, indexed by NodeIndex
+    ids: Vec<NodeId>,
+
+    // List of neighbors (and the corresponding edge) for each node, sorted by
+    // NodeIndex. Nodes are indexed by NodeIndex.
     neighbors: Vec<Vec<(NodeIndex, EdgeIndex)>>,
 
     // Indices of the two nodes of an edge, such that the first index is
@@ -35,38 +42,33 @@ where
     // to store state for each node and edge of a Graph with efficient lookup
     // and storage by using a CompactGraphState.
     pub fn new<Node, Edge>(graph: &NeighborGraph<NodeId, Node, Edge>) -> Self {
-        let node_indices = graph
-            .nodes
-            .iter()
+        CompactGraph::from_sorted_ids(
+            graph.sorted_node_ids(),
+            graph.sorted_edge_ids(),
+        )
+    }
+
+    // Create a compact graph without an underlying Graph, given the NodeIds
+    // in ascending order and the canonical NodeId pairs of the edges in
+    // ascending order, like Graph::sorted_node_ids and
+    // Graph::sorted_edge_ids return them.
+    pub fn from_sorted_ids(
+        ids: Vec<NodeId>,
+        edge_ids: Vec<(NodeId, NodeId)>,
+    ) -> Self {
+        let node_indices = ids.iter()
             .enumerate()
-            .map(|(i, (&id, _))| (id, i))
+            .map(|(i, &id)| (id, i))
             .collect::<HashMap<NodeId, NodeIndex>>();
-        let edge_indices =
-            graph
-                .edges
-                .iter()
-                .enumerate()
-                .map(|(i, (&(id_a, id_b), _))| ((id_a, id_b), i))
-                .collect::<CanonMap<(NodeId, NodeId), EdgeIndex>>();
-
-        let neighbors = graph
-            .nodes
+        let edge_indices = edge_ids
             .iter()
-            .map(|(&id_a, &(ref _node, ref neighbors))| {
-                let neighbor_indices = neighbors.iter().map(|id_b| {
-                    *node_indices.get(id_b).unwrap()
-                });
-                let edge_indices = neighbors.iter().map(|&id_b| {
-                    *edge_indices.get((id_a, id_b)).unwrap()
-                });
-                neighbor_indices.zip(edge_indices).collect()
-            })
-            .collect();
+            .enumerate()
+            .map(|(i, &(id_a, id_b))| ((id_a, id_b), i))
+            .collect::<CanonMap<(NodeId, NodeId), EdgeIndex>>();
 
-        let edges = graph
-            .edges
+        let edges = edge_ids
             .iter()
-            .map(|(&(id_a, id_b), _edge)| {
+            .map(|&(id_a, id_b)| {
                 let index_a = *node_indices.get(&id_a).unwrap();
                 let index_b = *node_indices.get(&id_b).unwrap();
 
@@ -78,7 +80,16 @@ where
                     (index_b, index_a)
                 }
             })
-            .collect();
+            .collect::<Vec<_>>();
+
+        let mut neighbors = vec![Vec::new(); ids.len()];
+        for (edge_index, &(index_a, index_b)) in edges.iter().enumerate() {
+            neighbors[index_a].push((index_b, edge_index));
+            neighbors[index_b].push((index_a, edge_index));
+        }
+        for node_neighbors in neighbors.iter_mut() {
+            node_neighbors.sort();
+        }
 
         let indices = Graph {
             nodes: node_indices,
@@ -87,6 +98,7 @@ where
 
         CompactGraph {
             indices: indices,
+            ids: ids,
             neighbors: neighbors,
             edges: edges,
         }
@@ -100,6 +112,10 @@ where
         *self.indices.nodes.get(&id).unwrap()
     }
 
+    pub fn node_id(&self, i: NodeIndex) -> NodeId {
+        self.ids[i]
+    }
+
     pub fn neighbors(&self, i: NodeIndex) -> &[(NodeIndex, EdgeIndex)] {
         &self.neighbors[i]
     }
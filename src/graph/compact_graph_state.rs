@@ -14,7 +14,9 @@ pub struct CompactGraphState<NodeState, EdgeState> {
 
 impl<NodeState, EdgeState> CompactGraphState<NodeState, EdgeState> {
     // Create a graph state for a CompactGraph. The node and edge state is
-    // initialized by mapping from the underlying Graph.
+    // initialized by mapping from the underlying Graph. The mapping functions
+    // are called in the order of the NodeIndex and EdgeIndex given by
+    // CompactGraph::new.
     pub fn new<NodeId, Node, Edge, FNode, FEdge>(
         graph: &NeighborGraph<NodeId, Node, Edge>,
         mut f_n: FNode,
@@ -26,15 +28,17 @@ impl<NodeState, EdgeState> CompactGraphState<NodeState, EdgeState> {
         FEdge: FnMut(NodeId, NodeId, &Edge) -> EdgeState,
     {
         let nodes = graph
-            .nodes
-            .iter()
-            .map(|(&id_a, &(ref node, ref _neighbors))| f_n(id_a, node))
+            .sorted_node_ids()
+            .into_iter()
+            .map(|id| f_n(id, &graph.nodes[&id].0))
             .collect();
 
         let edges = graph
-            .edges
-            .iter()
-            .map(|(&(id_a, id_b), edge)| f_e(id_a, id_b, edge))
+            .sorted_edge_ids()
+            .into_iter()
+            .map(|(id_a, id_b)| {
+                f_e(id_a, id_b, graph.edges.get((id_a, id_b)).unwrap())
+            })
             .collect();
 
         CompactGraphState {
@@ -43,6 +47,15 @@ impl<NodeState, EdgeState> CompactGraphState<NodeState, EdgeState> {
         }
     }
 
+    // Create a graph state from node and edge state that is already in the
+    // order of the NodeIndex and EdgeIndex of a CompactGraph
+    pub fn from_vecs(nodes: Vec<NodeState>, edges: Vec<EdgeState>) -> Self {
+        CompactGraphState {
+            nodes: nodes,
+            edges: edges,
+        }
+    }
+
     pub fn node(&self, i: NodeIndex) -> &NodeState {
         &self.nodes[i]
     }
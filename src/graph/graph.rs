@@ -11,6 +11,29 @@ pub struct Graph<NodeId: Copy + Eq + Ord + Hash, Node, Edge> {
     pub edges: CanonMap<(NodeId, NodeId), Edge>,
 }
 
+impl<NodeId, Node, Edge> Graph<NodeId, Node, Edge>
+where
+    NodeId: Copy + Eq + Ord + Hash,
+{
+    // NodeIds of all nodes in ascending order. Unlike iterating over the
+    // HashMap, this gives the same order every time.
+    pub fn sorted_node_ids(&self) -> Vec<NodeId> {
+        let mut ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    // Canonical NodeId pairs of all edges in ascending order
+    pub fn sorted_edge_ids(&self) -> Vec<(NodeId, NodeId)> {
+        let mut ids = self.edges
+            .iter()
+            .map(|(&ids, _edge)| ids)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+}
+
 // A graph in which each node additionally stores the NodeIds of its neighbors
 pub type NeighborGraph<NodeId, Node, Edge> = Graph<
     NodeId,
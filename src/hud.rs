@@ -11,9 +11,10 @@ use sdl2::keyboard;
 use types::{Dir, Rect, Axis};
 use input::{self, Input};
 use camera::Camera;
-use circuit::{self, ChipId, ChipDb, Circuit, Action, SwitchType, ComponentId,
-              Element};
+use circuit::{self, ChipId, ChipDb, Circuit, Action, SwitchType, GateType,
+              Threshold, ComponentId, Element};
 use display::{self, Display};
+use kinds;
 
 #[derive(Clone)]
 enum State {
@@ -34,6 +35,9 @@ enum State {
         prev_components: HashSet<ComponentId>,
     },
     Paste,
+
+    // Typing a new id for the current chip, see Hud::rename_chip
+    RenameChip { name: String },
 }
 
 pub struct Hud {
@@ -54,6 +58,9 @@ pub struct Hud {
     hold_shift: bool,
 
     grid_coords: circuit::Coords,
+
+    // Resistance of newly drawn edges
+    edge_resistance: f64,
 }
 
 fn screen_to_grid_pos(camera: &Camera, x: i32, y: i32) -> Vector2<f32> {
@@ -69,16 +76,16 @@ fn grid_pos_to_coords(p: Vector2<f32>) -> circuit::Coords {
     circuit::Coords::new(g_x, g_y)
 }
 
-fn screen_to_grid_coords(camera: &Camera, x: i32, y: i32) -> circuit::Coords {
+pub fn screen_to_grid_coords(
+    camera: &Camera,
+    x: i32,
+    y: i32,
+) -> circuit::Coords {
     grid_pos_to_coords(screen_to_grid_pos(camera, x, y))
 }
 
 fn is_selectable_element(element: &Element) -> bool {
-    match element {
-        &Element::Input { .. } => false,
-        &Element::Output { .. } => false,
-        _ => true,
-    }
+    !kinds::get(element).is_fixed()
 }
 
 fn selectable_components(
@@ -111,6 +118,7 @@ impl Hud {
             hold_control: false,
             hold_shift: false,
             grid_coords: circuit::Coords::new(0, 0),
+            edge_resistance: circuit::DEFAULT_RESISTANCE,
         };
         Ok(h)
     }
@@ -127,7 +135,7 @@ impl Hud {
     fn push_undo(&mut self, undo_action: Action) {
         //println!("undo: {:?}", undo_action);
 
-        self.undo.push((self.cur_chip_id, undo_action));
+        self.undo.push((self.cur_chip_id.clone(), undo_action));
 
         // After a user action is performed, clear redo
         self.redo.clear();
@@ -173,23 +181,89 @@ impl Hud {
         self.circuit(&self.cur_chip_id, circuit, chip_db)
     }
 
+    // Whether keys are used for typing, so that they should not be used for
+    // anything else
+    pub fn is_typing(&self) -> bool {
+        match self.state {
+            State::RenameChip { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Rename a chip of the library, see ChipDb::rename. The chip ids that the
+    // hud keeps, for example in undo and redo, are changed as well.
+    pub fn rename_chip(
+        &mut self,
+        old_id: &ChipId,
+        new_id: ChipId,
+        circuit: &mut Circuit,
+        chip_db: &mut ChipDb,
+    ) -> Result<(), String> {
+        chip_db.rename(old_id, new_id.clone(), circuit)?;
+
+        let rename = |chip_id: &mut Option<ChipId>| {
+            if chip_id.as_ref() == Some(old_id) {
+                *chip_id = Some(new_id.clone());
+            }
+        };
+
+        rename(&mut self.cur_chip_id);
+        for &mut (ref mut chip_id, ref mut action) in
+            self.undo.iter_mut().chain(self.redo.iter_mut())
+        {
+            rename(chip_id);
+            action.rename_chip(old_id, &new_id);
+        }
+        if let Some(ref mut clipboard) = self.clipboard {
+            clipboard.rename_chip(old_id, &new_id);
+        }
+        if let State::PlaceElement { ref mut element, .. } = self.state {
+            element.rename_chip(old_id, &new_id);
+        }
+
+        Ok(())
+    }
+
     pub fn switch_chip(&mut self, chip_id: &Option<ChipId>) {
         self.change_state(State::Initial);
         self.cur_chip_id = chip_id.clone();
     }
 
-    fn keycode_to_chip_id(&self, keycode: input::Keycode) -> Option<ChipId> {
-        match keycode {
-            input::Keycode::F2 => Some(2),
-            input::Keycode::F3 => Some(3),
-            input::Keycode::F4 => Some(4),
-            input::Keycode::F5 => Some(5),
-            input::Keycode::F6 => Some(6),
-            input::Keycode::F7 => Some(7),
-            input::Keycode::F8 => Some(8),
-            input::Keycode::F9 => Some(9),
-            input::Keycode::F10 => Some(10),
-            _ => None,
+    // F2 to F10 select the chips of the library in the order of their ids
+    fn keycode_to_chip_id(
+        &self,
+        chip_db: &ChipDb,
+        keycode: input::Keycode,
+    ) -> Option<ChipId> {
+        let index = match keycode {
+            input::Keycode::F2 => 0,
+            input::Keycode::F3 => 1,
+            input::Keycode::F4 => 2,
+            input::Keycode::F5 => 3,
+            input::Keycode::F6 => 4,
+            input::Keycode::F7 => 5,
+            input::Keycode::F8 => 6,
+            input::Keycode::F9 => 7,
+            input::Keycode::F10 => 8,
+            _ => return None,
+        };
+
+        chip_db.ids().get(index).cloned()
+    }
+
+    // Whether the chip components that are placed in the current state can go
+    // into the current circuit, see ChipDb::can_place
+    fn can_place(&self, chip_db: &ChipDb) -> bool {
+        match self.state {
+            State::PlaceElement { ref element, .. } => {
+                chip_db.can_place(element, &self.cur_chip_id)
+            }
+            State::Paste => self.clipboard.as_ref().map_or(true, |clipboard| {
+                clipboard.components().values().all(|component| {
+                    chip_db.can_place(&component.element, &self.cur_chip_id)
+                })
+            }),
+            _ => true,
         }
     }
 
@@ -213,6 +287,7 @@ impl Hud {
             State::Select { .. } => None,
             State::BoxSelect { .. } => None,
             State::Paste => None,
+            State::RenameChip { .. } => None,
         };
 
         if let Some(u) = undo_action {
@@ -260,6 +335,13 @@ impl Hud {
                     y,
                 );
             }
+            &Input::KeyDown {
+                keycode,
+                repeat: _,
+                ..
+            } if self.is_typing() => {
+                self.rename_key_down(circuit, chip_db, keycode);
+            }
             &Input::KeyDown {
                 keycode,
                 keymod,
@@ -277,13 +359,19 @@ impl Hud {
                     }
                     input::Keycode::Num2 => {
                         self.change_state(State::PlaceElement {
-                            element: Element::Switch(SwitchType::On),
+                            element: Element::Switch(
+                                SwitchType::On,
+                                Threshold::default(),
+                            ),
                             rotation_cw: 0,
                         });
                     }
                     input::Keycode::Num3 => {
                         self.change_state(State::PlaceElement {
-                            element: Element::Switch(SwitchType::Off),
+                            element: Element::Switch(
+                                SwitchType::Off,
+                                Threshold::default(),
+                            ),
                             rotation_cw: 0,
                         });
                     }
@@ -295,7 +383,9 @@ impl Hud {
                     }
                     input::Keycode::Num5 => {
                         self.change_state(State::PlaceElement {
-                            element: Element::Source,
+                            element: Element::Source {
+                                pressure: circuit::DEFAULT_PRESSURE,
+                            },
                             rotation_cw: 0,
                         });
                     }
@@ -307,17 +397,98 @@ impl Hud {
                     }
                     input::Keycode::Num7 => {
                         self.change_state(State::PlaceElement {
-                            element: Element::Power,
+                            element: Element::Power {
+                                threshold: Threshold::default(),
+                                pressure: circuit::DEFAULT_PRESSURE,
+                            },
+                            rotation_cw: 0,
+                        });
+                    }
+                    input::Keycode::G => {
+                        // Pressing G again cycles through the gate types
+                        let kind = match self.state {
+                            State::PlaceElement {
+                                element: Element::Gate {
+                                    kind: GateType::And, ..
+                                },
+                                ..
+                            } => GateType::Or,
+                            State::PlaceElement {
+                                element: Element::Gate {
+                                    kind: GateType::Or, ..
+                                },
+                                ..
+                            } => GateType::Xor,
+                            _ => GateType::And,
+                        };
+                        self.change_state(State::PlaceElement {
+                            element: Element::Gate {
+                                kind: kind,
+                                inputs: 2,
+                                threshold: Threshold::default(),
+                            },
+                            rotation_cw: 0,
+                        });
+                    }
+                    input::Keycode::Num8 => {
+                        self.change_state(State::PlaceElement {
+                            element: Element::Valve,
+                            rotation_cw: 0,
+                        });
+                    }
+                    input::Keycode::L => {
+                        // Pressing L again switches between on and off
+                        let on = match self.state {
+                            State::PlaceElement {
+                                element: Element::Toggle { on },
+                                ..
+                            } => !on,
+                            _ => true,
+                        };
+                        self.change_state(State::PlaceElement {
+                            element: Element::Toggle { on: on },
+                            rotation_cw: 0,
+                        });
+                    }
+                    input::Keycode::Num9 => {
+                        self.change_state(State::PlaceElement {
+                            element: Element::Reservoir {
+                                capacity: circuit::DEFAULT_CAPACITY,
+                            },
+                            rotation_cw: 0,
+                        });
+                    }
+                    input::Keycode::Num0 => {
+                        self.change_state(State::PlaceElement {
+                            element: Element::Clock {
+                                period: 10,
+                                duty: 5,
+                            },
+                            rotation_cw: 0,
+                        });
+                    }
+                    input::Keycode::P => {
+                        self.change_state(State::PlaceElement {
+                            element: Element::Pattern {
+                                bits: vec![true, false, true, true],
+                            },
                             rotation_cw: 0,
                         });
                     }
                     input::Keycode::F1 => {
                         self.switch_chip(&None);
                     }
+                    input::Keycode::LeftBracket => {
+                        self.edge_resistance = (self.edge_resistance / 2.0)
+                            .max(circuit::MIN_RESISTANCE);
+                    }
+                    input::Keycode::RightBracket => {
+                        self.edge_resistance = (self.edge_resistance * 2.0)
+                            .min(circuit::MAX_RESISTANCE);
+                    }
                     keycode => {
-                        if let Some(chip_id) = self.keycode_to_chip_id(
-                            keycode,
-                        )
+                        if let Some(chip_id) =
+                            self.keycode_to_chip_id(chip_db, keycode)
                         {
                             if keymod.contains(keyboard::LSHIFTMOD) {
                                 self.switch_chip(&Some(chip_id));
@@ -357,6 +528,50 @@ impl Hud {
         }
     }
 
+    // Keys change the id that is typed in State::RenameChip. Return renames
+    // the current chip, and Escape leaves the id as it is.
+    fn rename_key_down(
+        &mut self,
+        circuit: &mut Circuit,
+        chip_db: &mut ChipDb,
+        keycode: input::Keycode,
+    ) {
+        let mut name = match self.state {
+            State::RenameChip { ref name } => name.clone(),
+            _ => return,
+        };
+
+        match keycode {
+            input::Keycode::LCtrl => self.hold_control = true,
+            input::Keycode::LShift => self.hold_shift = true,
+            input::Keycode::Backspace => {
+                name.pop();
+            }
+            input::Keycode::Return => {
+                if let Some(old_id) = self.cur_chip_id.clone() {
+                    if name != old_id {
+                        let result =
+                            self.rename_chip(&old_id, name, circuit, chip_db);
+                        if let Err(err) = result {
+                            println!("Can't rename chip: {}", err);
+                        }
+                    }
+                }
+                self.change_state(State::Initial);
+                return;
+            }
+            input::Keycode::Escape => {
+                self.change_state(State::Initial);
+                return;
+            }
+            keycode => {
+                name.extend(keycode_to_chip_id_char(keycode, self.hold_shift));
+            }
+        }
+
+        self.state = State::RenameChip { name };
+    }
+
     fn mouse_motion_event(
         &mut self,
         _circuit: &mut Circuit,
@@ -376,6 +591,7 @@ impl Hud {
         x: i32,
         y: i32,
     ) {
+        let can_place = self.can_place(chip_db);
         let cur_circuit = self.cur_circuit_mut(circuit, chip_db);
         let grid_pos = screen_to_grid_pos(camera, x, y);
         let grid_coords = screen_to_grid_coords(camera, x, y);
@@ -423,7 +639,7 @@ impl Hud {
                 rotation_cw,
             } => {
                 match button {
-                    input::MouseButton::Left => {
+                    input::MouseButton::Left if can_place => {
                         // Use cursor pos as center if possible
                         let c = grid_coords - element.descr().size / 2;
 
@@ -463,7 +679,7 @@ impl Hud {
                 }
             }
             State::BoxSelect { .. } => {}
-            State::Paste => {
+            State::Paste if can_place => {
                 // Paste mode can only be entered when we have a clipboard entry.
                 // During Paste mode, the clipboard can not be changed.
                 // TODO: Might be able to remove the need to clone here
@@ -472,6 +688,8 @@ impl Hud {
                 let action = Action::PlaceCircuitAtPos(clipboard, grid_coords);
                 self.try_perform_action(cur_circuit, action);
             }
+            State::Paste => {}
+            State::RenameChip { .. } => {}
         }
     }
 
@@ -523,6 +741,7 @@ impl Hud {
                 self.change_state(state);
             }
             State::Paste => {}
+            State::RenameChip { .. } => {}
         }
     }
 
@@ -562,6 +781,13 @@ impl Hud {
                             self.change_state(State::Paste);
                         }
                     }
+                    input::Keycode::R if self.hold_control => {
+                        if let Some(chip_id) = self.cur_chip_id.clone() {
+                            self.change_state(State::RenameChip {
+                                name: chip_id,
+                            });
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -618,6 +844,51 @@ impl Hud {
             }
             _ => {}
         }
+
+        // Minus and Equals change the parameters of the element that is
+        // being placed, or of the selected component. Shift selects the
+        // second parameter, and Control the third one. Parameters that change
+        // the size of the component, like the number of inputs of a gate, can
+        // only be set before placing it, see Action::SetElement.
+        let factor = match keycode {
+            input::Keycode::Minus => 0.5,
+            input::Keycode::Equals => 2.0,
+            _ => return,
+        };
+        let param = if self.hold_control {
+            2
+        } else if self.hold_shift {
+            1
+        } else {
+            0
+        };
+
+        match self.state.clone() {
+            State::PlaceElement { element, rotation_cw } => {
+                if let Some(element) =
+                    adjust_element(&element, factor, param)
+                {
+                    self.state = State::PlaceElement {
+                        element,
+                        rotation_cw,
+                    };
+                }
+            }
+            State::Select { ref components } if components.len() == 1 => {
+                let id = *components.iter().next().unwrap();
+                let cur_circuit =
+                    self.circuit_mut(&self.cur_chip_id, circuit, chip_db);
+                let element = cur_circuit.components()[&id].element.clone();
+
+                if let Some(element) =
+                    adjust_element(&element, factor, param)
+                {
+                    let action = Action::SetElement(id, element);
+                    self.try_perform_action(cur_circuit, action);
+                }
+            }
+            _ => {}
+        }
     }
 
     pub fn update(
@@ -687,7 +958,9 @@ impl Hud {
 
                             if let Some(p) = prev_c {
                                 let dir = Dir::from_coords(p, c);
-                                let edge = circuit::Edge {};
+                                let edge = circuit::Edge {
+                                    resistance: self.edge_resistance,
+                                };
 
                                 let action =
                                     Action::PlaceEdgeAtPos(p, dir, Some(edge));
@@ -718,6 +991,7 @@ impl Hud {
                 *cur_grid_pos = grid_pos;
             }
             State::Paste => {}
+            State::RenameChip { .. } => {}
         }
     }
 
@@ -775,7 +1049,9 @@ impl Hud {
                 let component = element.new_component(c, rotation_cw);
                 let action = Action::PlaceComponent(component.clone());
 
-                let draw_mode = if action.can_perform(cur_circuit) {
+                let draw_mode = if action.can_perform(cur_circuit) &&
+                    self.can_place(chip_db)
+                {
                     display::DrawMode::Plan
                 } else {
                     display::DrawMode::Invalid
@@ -836,7 +1112,9 @@ impl Hud {
                     self.clipboard.clone().unwrap(),
                     self.grid_coords,
                 );
-                let draw_mode = if action.can_perform(cur_circuit) {
+                let draw_mode = if action.can_perform(cur_circuit) &&
+                    self.can_place(chip_db)
+                {
                     display::DrawMode::Plan
                 } else {
                     display::DrawMode::Invalid
@@ -886,15 +1164,83 @@ impl Hud {
             10.0 + coords_text.width() as f32 / 2.0, 30.0);
         coords_text.draw(ctx, coords_text_pos, 0.0)?;*/
 
-        let chip_str = match self.cur_chip_id {
-            Some(ref cur_chip_id) => format!("Chip {:?}", cur_chip_id),
-            None => format!("Main circuit"),
+        let chip_str = match (&self.cur_chip_id, &self.state) {
+            (&Some(ref cur_chip_id), &State::RenameChip { ref name }) => {
+                format!("Chip {}, rename to {}_", cur_chip_id, name)
+            }
+            (&Some(ref cur_chip_id), _) => format!("Chip {}", cur_chip_id),
+            (&None, _) => format!("Main circuit"),
         };
         let chip_text = graphics::Text::new(ctx, &chip_str, &self.font)?;
         let chip_text_pos =
             graphics::Point::new(10.0 + chip_text.width() as f32 / 2.0, 30.0);
         chip_text.draw(ctx, chip_text_pos, 0.0)?;
 
+        let resistance_str = format!("Resistance {}", self.edge_resistance);
+        let resistance_text =
+            graphics::Text::new(ctx, &resistance_str, &self.font)?;
+        let resistance_text_pos = graphics::Point::new(
+            10.0 + resistance_text.width() as f32 / 2.0,
+            50.0,
+        );
+        resistance_text.draw(ctx, resistance_text_pos, 0.0)?;
+
+        // Parameters of the element that is being placed or selected
+        let element = match self.state {
+            State::PlaceElement { ref element, .. } => Some(element.clone()),
+            State::Select { ref components } if components.len() == 1 => {
+                let id = components.iter().next().unwrap();
+                cur_circuit.components().get(id).map(|c| c.element.clone())
+            }
+            _ => None,
+        };
+        if let Some(params_str) = element.as_ref().and_then(element_params) {
+            let params_text =
+                graphics::Text::new(ctx, &params_str, &self.font)?;
+            let params_text_pos = graphics::Point::new(
+                10.0 + params_text.width() as f32 / 2.0,
+                70.0,
+            );
+            params_text.draw(ctx, params_text_pos, 0.0)?;
+        }
+
         Ok(())
     }
 }
+
+// Change a parameter of an element by the given factor, see
+// kinds::ElementKind::adjust
+fn adjust_element(
+    element: &Element,
+    factor: f64,
+    param: usize,
+) -> Option<Element> {
+    kinds::get(element).adjust(element, factor, param)
+}
+
+fn element_params(element: &Element) -> Option<String> {
+    kinds::get(element).params(element)
+}
+
+// Character that a key adds to a chip id, see chip_db::is_valid_chip_id. The
+// SDL key codes of printable keys are their ASCII characters.
+fn keycode_to_chip_id_char(
+    keycode: input::Keycode,
+    shift: bool,
+) -> Option<char> {
+    let code = keycode as i32;
+    if code < 0 || code >= 128 {
+        return None;
+    }
+
+    let c = match code as u8 as char {
+        '-' if shift => '_',
+        c if shift => c.to_ascii_uppercase(),
+        c => c,
+    };
+    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+        Some(c)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,102 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use flow;
+#[cfg(feature = "gui")]
+use kinds;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, DrawMode, EDGE_LENGTH};
+
+// Two crossing cells at the same grid point, one connecting up and down, the
+// other one left and right
+pub struct Bridge;
+
+impl ElementKind for Bridge {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr {
+            size: circuit::Coords::new(0, 0),
+            cells: vec![(Dir::Left, 0), (Dir::Left, 0)],
+            cell_edges: vec![
+                vec![Dir::Up, Dir::Down],
+                vec![Dir::Left, Dir::Right],
+            ],
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+        let r = graphics::Rect {
+            x: p_t.x,
+            y: p_t.y,
+            w: camera.transform_distance(EDGE_LENGTH / 2.0),
+            h: camera.transform_distance(EDGE_LENGTH / 2.0),
+        };
+        let inner_r = graphics::Rect {
+            x: p_t.x,
+            y: p_t.y,
+            w: camera.transform_distance(EDGE_LENGTH / 4.0),
+            h: camera.transform_distance(EDGE_LENGTH / 4.0),
+        };
+
+        graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
+        graphics::rectangle(ctx, graphics::DrawMode::Line, inner_r)?;
+
+        let left = Dir::Left.rotate_cw_n(c.rotation_cw);
+        let a = c.pos.cast() + left.delta().cast() * 0.25;
+        let b = a + left.invert().delta().cast() * 0.5;
+
+        let a_end = a + left.invert().delta().cast() * 0.125;
+        let b_end = b + left.delta().cast() * 0.125;
+
+        let a_t = camera.transform(a * EDGE_LENGTH);
+        let b_t = camera.transform(b * EDGE_LENGTH);
+        let a_end_t = camera.transform(a_end * EDGE_LENGTH);
+        let b_end_t = camera.transform(b_end * EDGE_LENGTH);
+
+        let a_p = graphics::Point::new(a_t.x, a_t.y);
+        let b_p = graphics::Point::new(b_t.x, b_t.y);
+        let a_end_p = graphics::Point::new(a_end_t.x, a_end_t.y);
+        let b_end_p = graphics::Point::new(b_end_t.x, b_end_t.y);
+
+        graphics::line(ctx, &vec![a_p, a_end_p])?;
+        graphics::line(ctx, &vec![b_p, b_end_p])
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        display: &Display,
+        ctx: &mut Context,
+        font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        if cell_index == 1 {
+            // The inner cell is drawn smaller, on top of the outer one
+            kinds::fill_cell(ctx, camera, c, cell_index, 0.5)?;
+            display.draw_component(ctx, font, camera, c, DrawMode::Real)
+        } else {
+            kinds::fill_cell(ctx, camera, c, cell_index, 1.0)
+        }
+    }
+}
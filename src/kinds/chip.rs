@@ -0,0 +1,69 @@
+use std::cmp;
+
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics::{self, Drawable};
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use kinds;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::Display;
+
+// A circuit from the ChipDb. Chips are replaced by their circuit before
+// simulating, see Circuit::unfold.
+pub struct Chip;
+
+impl ElementKind for Chip {
+    fn descr(&self, element: &Element) -> ElementDescr {
+        // kinds::get only gives this kind for chips
+        let descr = match element {
+            &Element::Chip(ref _id, ref descr) => descr,
+            _ => unreachable!("chip kind used for {:?}", element),
+        };
+
+        let height = cmp::max(descr.left_size, descr.right_size);
+        let left_cells = (0..descr.left_size).map(|i| (Dir::Left, i));
+        let right_cells = (0..descr.right_size).map(|i| (Dir::Right, i)).rev();
+        let cells = left_cells.chain(right_cells).collect();
+        let left_edges = (0..descr.left_size).map(|_| vec![Dir::Left]);
+        let right_edges = (0..descr.right_size).map(|_| vec![Dir::Right]);
+        let edges = left_edges.chain(right_edges).collect();
+
+        ElementDescr {
+            size: circuit::Coords::new(1, height as isize - 1),
+            cells: cells,
+            cell_edges: edges,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        display: &Display,
+        ctx: &mut Context,
+        font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let chip_str = match c.element {
+            Element::Chip(ref chip_id, _) => format!("{}", chip_id),
+            _ => return Ok(()),
+        };
+
+        display.draw_component_edges(ctx, camera, c)?;
+        let center =
+            kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Line, 0.5)?;
+
+        let chip_text = graphics::Text::new(ctx, &chip_str, font)?;
+        let chip_text_pos = graphics::Point::new(center.x, center.y);
+        chip_text.draw(ctx, chip_text_pos, 0.0)
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(feature = "gui")]
+use cgmath::Vector2;
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics::{self, Drawable};
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr, GateType};
+use flow::{self, CellInit, CellRole};
+use kinds::{self, ElementKind};
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH, HALF_EDGE_LENGTH};
+
+// A column of control cells, with the flow cell at the top. Like a switch, but
+// the flow cell depends on the combination of the control cells.
+pub struct Gate;
+
+impl ElementKind for Gate {
+    fn descr(&self, element: &Element) -> ElementDescr {
+        let inputs = match element {
+            &Element::Gate { inputs, .. } => inputs,
+            _ => 0,
+        };
+
+        ElementDescr {
+            size: circuit::Coords::new(0, inputs as isize - 1),
+            cells: (0..inputs)
+                .map(|i| (Dir::Left, i))
+                .chain(Some((Dir::Right, 0)))
+                .collect(),
+            cell_edges: (0..inputs)
+                .map(|_| vec![Dir::Left])
+                .chain(Some(vec![Dir::Up, Dir::Right]))
+                .collect(),
+        }
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Gate {
+                inputs,
+                threshold,
+                ..
+            } => Some(format!(
+                "Threshold on {} off {}, inputs {}",
+                threshold.on,
+                threshold.off,
+                inputs
+            )),
+            _ => None,
+        }
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        match element {
+            &Element::Gate {
+                kind,
+                inputs,
+                threshold,
+            } if param < 3 => {
+                if param < 2 {
+                    Some(Element::Gate {
+                        kind: kind,
+                        inputs: inputs,
+                        threshold: kinds::adjust_threshold(
+                            threshold,
+                            factor,
+                            param,
+                        ),
+                    })
+                } else {
+                    // One input more or less, since doubling would skip most
+                    // counts
+                    let inputs = if factor > 1.0 {
+                        inputs + 1
+                    } else {
+                        inputs - 1
+                    };
+                    Some(Element::Gate {
+                        kind: kind,
+                        inputs: inputs.max(2),
+                        threshold: threshold,
+                    })
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, element: &Element, cell_index: usize) -> CellInit {
+        let inputs = match element {
+            &Element::Gate { inputs, .. } => inputs,
+            _ => 0,
+        };
+
+        if cell_index < inputs {
+            CellInit {
+                pressure: Some(0.0),
+                role: CellRole::Sink,
+            }
+        } else {
+            CellInit {
+                pressure: None,
+                role: CellRole::Inner,
+            }
+        }
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        let (kind, inputs, threshold) = match component.element {
+            Element::Gate {
+                kind,
+                inputs,
+                threshold,
+            } => (kind, inputs, threshold),
+            _ => return None,
+        };
+
+        for i in 0..inputs {
+            let active = component.controls_active[i];
+            component.controls_active[i] =
+                kinds::control_active(state, component, i, threshold, active);
+        }
+
+        let num_active =
+            component.controls_active.iter().filter(|&&a| a).count();
+        let enabled = match kind {
+            GateType::And => num_active == inputs,
+            GateType::Or => num_active > 0,
+            GateType::Xor => num_active % 2 == 1,
+        };
+        component.active = enabled;
+        kinds::set_cell_edges_enabled(state, component, inputs, enabled);
+
+        Some(enabled)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let (kind, inputs) = match c.element {
+            Element::Gate { kind, inputs, .. } => (kind, inputs),
+            _ => return Ok(()),
+        };
+
+        let center =
+            kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Line, 0.5)?;
+
+        // Mark the control cells like the one of a switch
+        let left_dir = Dir::Left.rotate_cw_n(c.rotation_cw);
+        for control_p in c.cells[0..inputs].iter() {
+            let p: Vector2<f32> =
+                control_p.cast() + left_dir.delta().cast() * 0.25;
+            let p_t = camera.transform(p * EDGE_LENGTH);
+            graphics::circle(
+                ctx,
+                graphics::DrawMode::Fill,
+                graphics::Point { x: p_t.x, y: p_t.y },
+                camera.transform_distance(HALF_EDGE_LENGTH * 0.3),
+                50,
+            )?;
+        }
+
+        let kind_str = match kind {
+            GateType::And => "and",
+            GateType::Or => "or",
+            GateType::Xor => "xor",
+        };
+        let text = graphics::Text::new(ctx, kind_str, font)?;
+        let text_pos = graphics::Point::new(center.x, center.y);
+        text.draw(ctx, text_pos, 0.0)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        kinds::fill_cell(ctx, camera, c, cell_index, 0.5)
+    }
+}
@@ -0,0 +1,185 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+use flow::{self, CellInit, CellRole};
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use kinds;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::Display;
+
+// The inputs and outputs of a level or a chip. Each input cell is a source
+// while it is enabled, see flow::Cell::enabled.
+pub struct Input;
+
+pub struct Output;
+
+fn column_descr(size: usize) -> ElementDescr {
+    ElementDescr::with_outer_edges(
+        circuit::Coords::new(0, size as isize - 1),
+        (0..size).map(|i| (Dir::Left, i)).collect(),
+    )
+}
+
+impl ElementKind for Input {
+    fn descr(&self, element: &Element) -> ElementDescr {
+        match element {
+            &Element::Input { size, .. } => column_descr(size),
+            _ => column_descr(0),
+        }
+    }
+
+    fn is_fixed(&self) -> bool {
+        true
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        self.pressure(element).map(|pressure| format!("Pressure {}", pressure))
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        match element {
+            &Element::Input { size, pressure } if param == 0 => {
+                Some(Element::Input {
+                    size: size,
+                    pressure: pressure * factor,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn pressure(&self, element: &Element) -> Option<f64> {
+        match element {
+            &Element::Input { pressure, .. } => Some(pressure),
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, element: &Element, _cell_index: usize) -> CellInit {
+        match element {
+            &Element::Input { size, pressure } => CellInit {
+                pressure: Some(pressure),
+                role: CellRole::Input(size),
+            },
+            _ => CellInit {
+                pressure: None,
+                role: CellRole::Inner,
+            },
+        }
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        if let Element::Input { pressure, .. } = component.element {
+            for &cell_idx in component.cells.iter() {
+                let input_cell = state.flow.node_mut(cell_idx);
+                input_cell.bound_pressure = input_cell.enabled;
+                input_cell.pressure = pressure;
+            }
+        }
+
+        None
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Fill, 0.5)?;
+        graphics::set_color(ctx, graphics::Color::new(0.0, 0.0, 0.0, 1.0))?;
+        kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Fill, 0.25)?;
+        Ok(())
+    }
+
+    // The cells are not shown, they are controlled by the level
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        _ctx: &mut Context,
+        _font: &graphics::Font,
+        _camera: &Camera,
+        _c: &Component,
+        _cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        Ok(())
+    }
+}
+
+impl ElementKind for Output {
+    fn descr(&self, element: &Element) -> ElementDescr {
+        match element {
+            &Element::Output { size } => column_descr(size),
+            _ => column_descr(0),
+        }
+    }
+
+    fn is_fixed(&self) -> bool {
+        true
+    }
+
+    fn init_cell(&self, element: &Element, _cell_index: usize) -> CellInit {
+        match element {
+            &Element::Output { size } => CellInit {
+                pressure: Some(0.0),
+                role: CellRole::Output(size),
+            },
+            _ => CellInit {
+                pressure: None,
+                role: CellRole::Inner,
+            },
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Line, 0.5)?;
+        kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Line, 0.25)?;
+        Ok(())
+    }
+
+    // The cells are not shown, they are controlled by the level
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        _ctx: &mut Context,
+        _font: &graphics::Font,
+        _camera: &Camera,
+        _c: &Component,
+        _cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+// Everything that depends on the type of an element, kept in one place. Each
+// type of element implements ElementKind, and `get` finds the kind of an
+// element. Adding an element means adding a variant to circuit::Element,
+// implementing ElementKind for it, and adding it to `get` and to the file
+// format in circuit::file.
+//
+// The methods of ElementKind take the element itself as an argument, and
+// each kind only needs to handle the variant it belongs to.
+
+mod node;
+mod bridge;
+mod switch;
+mod gate;
+mod source;
+mod io;
+mod power;
+mod valve;
+mod toggle;
+mod reservoir;
+mod timed;
+mod chip;
+
+#[cfg(feature = "gui")]
+use cgmath::Vector2;
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use circuit::{Element, ElementDescr, Threshold};
+use flow::{self, CellInit, CellRole};
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+pub trait ElementKind {
+    // Size, cells and cell edges of the element before rotation
+    fn descr(&self, element: &Element) -> ElementDescr;
+
+    // Whether components of this kind belong to the level or the chip that
+    // is edited, so that they can not be selected or removed in the editor
+    fn is_fixed(&self) -> bool {
+        false
+    }
+
+    // The parameters of the element that can be changed in the editor
+    fn params(&self, _element: &Element) -> Option<String> {
+        None
+    }
+
+    // Change a parameter of the element by the given factor. Parameters are
+    // numbered in the order in which they are shown by `params`. Counts step
+    // by one instead, up for a factor above 1 and down otherwise. Returns
+    // None if the element does not have the parameter.
+    fn adjust(
+        &self,
+        _element: &Element,
+        _factor: f64,
+        _param: usize,
+    ) -> Option<Element> {
+        None
+    }
+
+    // Pressure that the element supplies, if it has one
+    fn pressure(&self, _element: &Element) -> Option<f64> {
+        None
+    }
+
+    // Pairs of cells of the element that are connected in the simulation.
+    // These edges are not part of the circuit, so they can not be edited.
+    fn internal_edges(&self, _element: &Element) -> Vec<(usize, usize)> {
+        Vec::new()
+    }
+
+    // How a cell of the element takes part in the simulation at the start
+    fn init_cell(&self, _element: &Element, _cell_index: usize) -> CellInit {
+        CellInit {
+            pressure: None,
+            role: CellRole::Inner,
+        }
+    }
+
+    // Value of flow::Component::active at the start
+    fn init_active(&self, _element: &Element) -> bool {
+        false
+    }
+
+    // Update the component at the start of a tick, based on the flow of the
+    // previous tick. Components that enable and disable parts of the circuit
+    // return whether they are enabled, these are the ones that are settled,
+    // see flow::State::settle_iterations.
+    //
+    // While the components are updated, they are not in state.components.
+    fn update(
+        &self,
+        _component: &mut flow::Component,
+        _state: &mut flow::State,
+    ) -> Option<bool> {
+        None
+    }
+
+    // Update the component after the pressure has been solved. Returns
+    // whether the component changed in a way that needs the pressure to be
+    // solved again.
+    fn update_after_solve(
+        &self,
+        _component: &mut flow::Component,
+        _state: &mut flow::State,
+    ) -> bool {
+        false
+    }
+
+    // Update the component after the fluid has been moved in a time step of
+    // dt seconds
+    fn update_after_flow(
+        &self,
+        _component: &mut flow::Component,
+        _state: &mut flow::State,
+        _dt: f64,
+    ) {
+    }
+
+    // React to the component being clicked while the simulation is running
+    fn click(
+        &self,
+        _component: &mut flow::Component,
+        _state: &mut flow::State,
+    ) {
+    }
+
+    // Draw the component in the editor. The color has been set already.
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        display: &Display,
+        ctx: &mut Context,
+        font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()>;
+
+    // Draw the state of the component while the simulation is running, on
+    // top of its cells
+    #[cfg(feature = "gui")]
+    fn draw_state(
+        &self,
+        _display: &Display,
+        _ctx: &mut Context,
+        _font: &graphics::Font,
+        _camera: &Camera,
+        _c: &Component,
+        _component: &flow::Component,
+    ) -> GameResult<()> {
+        Ok(())
+    }
+
+    // Draw a cell of the component while the simulation is running. The
+    // color has been set to show the pressure of the cell.
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        fill_cell(ctx, camera, c, cell_index, 1.0)
+    }
+}
+
+pub fn get(element: &Element) -> &'static ElementKind {
+    match element {
+        &Element::Node => &node::Node,
+        &Element::Bridge => &bridge::Bridge,
+        &Element::Switch(..) => &switch::Switch,
+        &Element::Gate { .. } => &gate::Gate,
+        &Element::Source { .. } => &source::Source,
+        &Element::Sink => &source::Sink,
+        &Element::Input { .. } => &io::Input,
+        &Element::Output { .. } => &io::Output,
+        &Element::Power { .. } => &power::Power,
+        &Element::Valve => &valve::Valve,
+        &Element::Toggle { .. } => &toggle::Toggle,
+        &Element::Reservoir { .. } => &reservoir::Reservoir,
+        &Element::Clock { .. } => &timed::Clock,
+        &Element::Pattern { .. } => &timed::Pattern,
+        &Element::Chip(..) => &chip::Chip,
+    }
+}
+
+// Whether the control cell with the given index is active after the last
+// tick, see circuit::Threshold
+fn control_active(
+    state: &flow::State,
+    component: &flow::Component,
+    cell_index: usize,
+    threshold: Threshold,
+    active: bool,
+) -> bool {
+    let control_cell = state.flow.node(component.cells[cell_index]);
+    threshold.apply(active, control_cell.in_flow)
+}
+
+// Enable or disable all edges of a cell
+fn set_cell_edges_enabled(
+    state: &mut flow::State,
+    component: &flow::Component,
+    cell_index: usize,
+    enabled: bool,
+) {
+    for &(_, edge_idx) in state.graph.neighbors(component.cells[cell_index]) {
+        state.flow.edge_mut(edge_idx).enabled = enabled;
+    }
+}
+
+// The first parameter of a threshold is the `on` level, the second one the
+// `off` level. The other level is moved along if needed, so that the
+// threshold stays valid.
+fn adjust_threshold(
+    threshold: Threshold,
+    factor: f64,
+    param: usize,
+) -> Threshold {
+    if param == 1 {
+        let off = threshold.off * factor;
+        Threshold {
+            on: threshold.on.max(off),
+            off: off,
+        }
+    } else {
+        let on = threshold.on * factor;
+        Threshold {
+            on: on,
+            off: threshold.off.min(on),
+        }
+    }
+}
+
+// Fill a square around the position of a cell. A scale of 1 gives the size
+// that is used for most cells.
+#[cfg(feature = "gui")]
+fn fill_cell(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+    cell_index: usize,
+    scale: f32,
+) -> GameResult<()> {
+    let p: Vector2<f32> = c.cells[cell_index].cast();
+    let p_t = camera.transform(p * EDGE_LENGTH);
+    let size = camera.transform_distance(EDGE_LENGTH * 0.45) * scale;
+
+    let r = graphics::Rect {
+        x: p_t.x,
+        y: p_t.y,
+        w: size,
+        h: size,
+    };
+    graphics::rectangle(ctx, graphics::DrawMode::Fill, r)
+}
+
+// Like fill_cell, but with a circle
+#[cfg(feature = "gui")]
+fn fill_cell_circle(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+    cell_index: usize,
+) -> GameResult<()> {
+    let p: Vector2<f32> = c.cells[cell_index].cast();
+    let p_t = camera.transform(p * EDGE_LENGTH);
+    let size = camera.transform_distance(EDGE_LENGTH * 0.45);
+
+    graphics::circle(
+        ctx,
+        graphics::DrawMode::Fill,
+        graphics::Point { x: p_t.x, y: p_t.y },
+        size / 2.0,
+        50,
+    )
+}
+
+// Draw a rect around the whole component, for elements that are larger than
+// a single grid point. Returns the center in screen coordinates.
+#[cfg(feature = "gui")]
+fn draw_outline(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+    mode: graphics::DrawMode,
+    margin: f32,
+) -> GameResult<Vector2<f32>> {
+    let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+    let size = (c.size().cast() + Vector2::new(margin, margin)) * EDGE_LENGTH;
+    let trans_size = camera.transform_delta(size);
+    let shift = c.size().cast() * (EDGE_LENGTH / 2.0);
+    let trans_shift = camera.transform_delta(shift);
+    let center = p_t + trans_shift;
+
+    let r = graphics::Rect {
+        x: center.x,
+        y: center.y,
+        w: trans_size.x,
+        h: trans_size.y,
+    };
+    graphics::rectangle(ctx, mode, r)?;
+
+    Ok(center)
+}
@@ -0,0 +1,45 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+#[cfg(feature = "gui")]
+use circuit::Component;
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+pub struct Node;
+
+impl ElementKind for Node {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr::with_outer_edges(
+            circuit::Coords::new(0, 0),
+            vec![(Dir::Left, 0)],
+        )
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+        let r = graphics::Rect {
+            x: p_t.x,
+            y: p_t.y,
+            w: camera.transform_distance(EDGE_LENGTH / 2.0),
+            h: camera.transform_distance(EDGE_LENGTH / 2.0),
+        };
+
+        graphics::rectangle(ctx, graphics::DrawMode::Line, r)
+    }
+}
@@ -0,0 +1,168 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+use flow::{self, CellInit, CellRole};
+use kinds::{self, ElementKind};
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+// Cell 0 is the control cell. While it is active, cell 1 is bound to the
+// pressure of the power component.
+pub struct Power;
+
+impl ElementKind for Power {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr {
+            size: circuit::Coords::new(0, 0),
+            cells: vec![(Dir::Left, 0), (Dir::Left, 0)],
+            cell_edges: vec![vec![Dir::Left], vec![Dir::Right]],
+        }
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Power {
+                threshold,
+                pressure,
+            } => Some(format!(
+                "Threshold on {} off {}, pressure {}",
+                threshold.on,
+                threshold.off,
+                pressure
+            )),
+            _ => None,
+        }
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        match element {
+            &Element::Power {
+                threshold,
+                pressure,
+            } if param < 3 => {
+                if param < 2 {
+                    Some(Element::Power {
+                        threshold: kinds::adjust_threshold(
+                            threshold,
+                            factor,
+                            param,
+                        ),
+                        pressure: pressure,
+                    })
+                } else {
+                    Some(Element::Power {
+                        threshold: threshold,
+                        pressure: pressure * factor,
+                    })
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn pressure(&self, element: &Element) -> Option<f64> {
+        match element {
+            &Element::Power { pressure, .. } => Some(pressure),
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, _element: &Element, cell_index: usize) -> CellInit {
+        CellInit {
+            pressure: if cell_index == 0 { Some(0.0) } else { None },
+            role: CellRole::Inner,
+        }
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        let (threshold, pressure) = match component.element {
+            Element::Power {
+                threshold,
+                pressure,
+            } => (threshold, pressure),
+            _ => return None,
+        };
+
+        let active = component.active;
+        component.active =
+            kinds::control_active(state, component, 0, threshold, active);
+        let enabled = component.active;
+
+        let power_cell = state.flow.node_mut(component.cells[1]);
+        power_cell.bound_pressure = enabled;
+        power_cell.pressure = pressure;
+
+        Some(enabled)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        fill_triangle(ctx, camera, c)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        _cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        fill_triangle(ctx, camera, c)
+    }
+}
+
+// Triangle pointing from the control cell to the power cell
+#[cfg(feature = "gui")]
+fn fill_triangle(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+) -> GameResult<()> {
+    // Corner position of the nodes
+    let dir = Dir::Left.rotate_cw_n(c.rotation_cw);
+    let orth_dir = dir.rotate_cw();
+    let left = c.pos.cast() + dir.delta().cast() / 4.0;
+    let x = left + orth_dir.delta().cast() / 4.0;
+    let y = left + orth_dir.invert().delta().cast() / 4.0;
+    let z = c.pos.cast() + dir.invert().delta().cast() / 4.0;
+
+    let x_t = camera.transform(x * EDGE_LENGTH);
+    let y_t = camera.transform(y * EDGE_LENGTH);
+    let z_t = camera.transform(z * EDGE_LENGTH);
+
+    let vertices = vec![
+        graphics::Point { x: x_t.x, y: x_t.y },
+        graphics::Point { x: y_t.x, y: y_t.y },
+        graphics::Point { x: z_t.x, y: z_t.y },
+    ];
+
+    graphics::polygon(ctx, graphics::DrawMode::Fill, &vertices)
+}
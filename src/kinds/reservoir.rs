@@ -0,0 +1,155 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr, DEFAULT_PRESSURE};
+use flow::{self, SimulationMode, CellInit, CellRole};
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, DrawMode, EDGE_LENGTH};
+
+// The cell of a reservoir always has bound pressure, which is proportional to
+// its fill, see flow::Cell::fill.
+pub struct Reservoir;
+
+impl ElementKind for Reservoir {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr::with_outer_edges(
+            circuit::Coords::new(0, 0),
+            vec![(Dir::Left, 0)],
+        )
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Reservoir { capacity } => {
+                Some(format!("Capacity {}", capacity))
+            }
+            _ => None,
+        }
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        match element {
+            &Element::Reservoir { capacity } if param == 0 => {
+                Some(Element::Reservoir { capacity: capacity * factor })
+            }
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, _element: &Element, _cell_index: usize) -> CellInit {
+        CellInit {
+            pressure: Some(0.0),
+            role: CellRole::Inner,
+        }
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        if let Element::Reservoir { capacity } = component.element {
+            let cell = state.flow.node_mut(component.cells[0]);
+            cell.pressure = DEFAULT_PRESSURE * cell.fill / capacity;
+        }
+
+        None
+    }
+
+    // Fill the reservoir with the flow that went into it during the last
+    // tick, and drain it by the flow that came out of it. A reservoir can not
+    // hold more than its capacity, and anything beyond that spills out of the
+    // circuit. It also can not give more than it holds; if the flow took more
+    // than that (which can only happen when the capacity is tiny compared to
+    // the flow), the difference is counted as supplied.
+    fn update_after_flow(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+        dt: f64,
+    ) {
+        let capacity = match component.element {
+            Element::Reservoir { capacity } => capacity,
+            _ => return,
+        };
+
+        let cell = state.flow.node_mut(component.cells[0]);
+        let fill = cell.fill + (cell.in_flow - cell.out_flow) * dt;
+        cell.fill = fill.max(0.0).min(capacity);
+
+        // In SimulationMode::Transport, the reservoir has been treated as a
+        // boundary cell. The fluid that went through it did not leave or
+        // enter the circuit though, it is only stored.
+        if state.mode == SimulationMode::Transport {
+            state.supplied += (cell.fill - fill).max(0.0) -
+                cell.out_flow * dt;
+            state.absorbed += (fill - cell.fill).max(0.0) -
+                cell.in_flow * dt;
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+        let r = graphics::Rect {
+            x: p_t.x,
+            y: p_t.y,
+            w: camera.transform_distance(EDGE_LENGTH * 0.6),
+            h: camera.transform_distance(EDGE_LENGTH * 0.6),
+        };
+
+        graphics::rectangle(ctx, graphics::DrawMode::Line, r)
+    }
+
+    // The height of the rect shows how full the reservoir is
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        display: &Display,
+        ctx: &mut Context,
+        font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        _cell_index: usize,
+        cell: &flow::Cell,
+    ) -> GameResult<()> {
+        let capacity = match c.element {
+            Element::Reservoir { capacity } => capacity,
+            _ => return Ok(()),
+        };
+
+        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+        let full_size = camera.transform_distance(EDGE_LENGTH * 0.6);
+        let level = (cell.fill / capacity) as f32;
+        let height = full_size * level;
+        let r = graphics::Rect {
+            x: p_t.x,
+            y: p_t.y + (full_size - height) / 2.0,
+            w: full_size,
+            h: height,
+        };
+
+        graphics::rectangle(ctx, graphics::DrawMode::Fill, r)?;
+        display.draw_component(ctx, font, camera, c, DrawMode::Real)
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(feature = "gui")]
+use cgmath::Vector2;
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr, DEFAULT_PRESSURE};
+use flow::{CellInit, CellRole};
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use flow;
+#[cfg(feature = "gui")]
+use kinds;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+pub struct Source;
+
+pub struct Sink;
+
+impl ElementKind for Source {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr::with_outer_edges(
+            circuit::Coords::new(0, 0),
+            vec![(Dir::Right, 0)],
+        )
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        self.pressure(element).map(|pressure| format!("Pressure {}", pressure))
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        match element {
+            &Element::Source { pressure } if param == 0 => {
+                Some(Element::Source { pressure: pressure * factor })
+            }
+            _ => None,
+        }
+    }
+
+    fn pressure(&self, element: &Element) -> Option<f64> {
+        match element {
+            &Element::Source { pressure } => Some(pressure),
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, element: &Element, _cell_index: usize) -> CellInit {
+        CellInit {
+            pressure: Some(self.pressure(element).unwrap_or(DEFAULT_PRESSURE)),
+            role: CellRole::Source,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        draw_source_or_sink(ctx, camera, c, false)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        kinds::fill_cell_circle(ctx, camera, c, cell_index)
+    }
+}
+
+impl ElementKind for Sink {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr::with_outer_edges(
+            circuit::Coords::new(0, 0),
+            vec![(Dir::Left, 0)],
+        )
+    }
+
+    fn init_cell(&self, _element: &Element, _cell_index: usize) -> CellInit {
+        CellInit {
+            pressure: Some(0.0),
+            role: CellRole::Sink,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        draw_source_or_sink(ctx, camera, c, true)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        kinds::fill_cell_circle(ctx, camera, c, cell_index)
+    }
+}
+
+#[cfg(feature = "gui")]
+fn draw_source_or_sink(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+    is_sink: bool,
+) -> GameResult<()> {
+    let size = (c.size().cast() + Vector2::new(0.5, 0.5)) * EDGE_LENGTH;
+    let center =
+        kinds::draw_outline(ctx, camera, c, graphics::DrawMode::Line, 0.5)?;
+
+    graphics::circle(
+        ctx,
+        graphics::DrawMode::Fill,
+        graphics::Point {
+            x: center.x,
+            y: center.y,
+        },
+        camera.transform_distance(size.x / 2.0),
+        50,
+    )?;
+
+    if is_sink {
+        graphics::set_color(ctx, graphics::Color::new(0.0, 0.0, 0.0, 1.0))?;
+        graphics::circle(
+            ctx,
+            graphics::DrawMode::Fill,
+            graphics::Point {
+                x: center.x,
+                y: center.y,
+            },
+            camera.transform_distance(size.x / 2.0 - 0.05),
+            50,
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,167 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr, SwitchType};
+use flow::{self, CellInit, CellRole};
+use kinds::{self, ElementKind};
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH, HALF_EDGE_LENGTH};
+
+// Cell 0 is the control cell, which absorbs any flow into it. Cell 1 is the
+// flow cell, whose edges are enabled depending on the flow into the control
+// cell.
+pub struct Switch;
+
+impl ElementKind for Switch {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr {
+            size: circuit::Coords::new(0, 0),
+            cells: vec![(Dir::Left, 0), (Dir::Right, 0)],
+            cell_edges: vec![
+                vec![Dir::Left],
+                vec![Dir::Right, Dir::Up, Dir::Down],
+            ],
+        }
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Switch(_, threshold) => Some(format!(
+                "Threshold on {} off {}",
+                threshold.on,
+                threshold.off
+            )),
+            _ => None,
+        }
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        match element {
+            &Element::Switch(kind, threshold) if param < 2 => {
+                let threshold =
+                    kinds::adjust_threshold(threshold, factor, param);
+                Some(Element::Switch(kind, threshold))
+            }
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, _element: &Element, cell_index: usize) -> CellInit {
+        if cell_index == 0 {
+            CellInit {
+                pressure: Some(0.0),
+                role: CellRole::Sink,
+            }
+        } else {
+            CellInit {
+                pressure: None,
+                role: CellRole::Inner,
+            }
+        }
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        let (kind, threshold) = match component.element {
+            Element::Switch(kind, threshold) => (kind, threshold),
+            _ => return None,
+        };
+
+        let active = component.active;
+        component.active =
+            kinds::control_active(state, component, 0, threshold, active);
+        let enabled = match kind {
+            SwitchType::On => component.active,
+            SwitchType::Off => !component.active,
+        };
+        kinds::set_cell_edges_enabled(state, component, 1, enabled);
+
+        Some(enabled)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let kind = match c.element {
+            Element::Switch(kind, _) => kind,
+            _ => return Ok(()),
+        };
+
+        let left_dir = Dir::Left.rotate_cw_n(c.rotation_cw);
+        let flow_p = c.cells[1].cast();
+        let control_p = flow_p + left_dir.delta().cast() * 0.25;
+
+        let flow_p_t = camera.transform(flow_p * EDGE_LENGTH);
+        let control_p_t = camera.transform(control_p * EDGE_LENGTH);
+
+        graphics::circle(
+            ctx,
+            graphics::DrawMode::Fill,
+            graphics::Point {
+                x: control_p_t.x,
+                y: control_p_t.y,
+            },
+            camera.transform_distance(HALF_EDGE_LENGTH * 0.3),
+            50,
+        )?;
+
+        let r = graphics::Rect {
+            x: flow_p_t.x,
+            y: flow_p_t.y,
+            w: camera.transform_distance(HALF_EDGE_LENGTH),
+            h: camera.transform_distance(HALF_EDGE_LENGTH),
+        };
+        graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
+
+        if kind == SwitchType::Off {
+            graphics::set_color(ctx, graphics::Color::new(0.0, 0.0, 0.0, 1.0))?;
+            graphics::circle(
+                ctx,
+                graphics::DrawMode::Fill,
+                graphics::Point {
+                    x: control_p_t.x,
+                    y: control_p_t.y,
+                },
+                camera.transform_distance(HALF_EDGE_LENGTH * 0.25),
+                50,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        kinds::fill_cell(ctx, camera, c, cell_index, 0.5)
+    }
+}
@@ -0,0 +1,232 @@
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics::{self, Drawable};
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr, DEFAULT_PRESSURE, pattern_to_string};
+use flow::{self, CellInit, CellRole};
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use kinds;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+// Sources that are switched on and off depending on flow::State::tick
+pub struct Clock;
+
+pub struct Pattern;
+
+fn timed_descr() -> ElementDescr {
+    ElementDescr::with_outer_edges(
+        circuit::Coords::new(0, 0),
+        vec![(Dir::Right, 0)],
+    )
+}
+
+// The cell is bound in `update`, depending on the tick
+fn timed_init_cell() -> CellInit {
+    CellInit {
+        pressure: None,
+        role: CellRole::Source,
+    }
+}
+
+fn set_on(component: &flow::Component, state: &mut flow::State, on: bool) {
+    let cell = state.flow.node_mut(component.cells[0]);
+    cell.bound_pressure = on;
+    cell.pressure = DEFAULT_PRESSURE;
+}
+
+impl ElementKind for Clock {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        timed_descr()
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Clock { period, duty } => {
+                Some(format!("Period {} duty {}", period, duty))
+            }
+            _ => None,
+        }
+    }
+
+    fn adjust(
+        &self,
+        element: &Element,
+        factor: f64,
+        param: usize,
+    ) -> Option<Element> {
+        let (period, duty) = match element {
+            &Element::Clock { period, duty } if param < 2 => (period, duty),
+            _ => return None,
+        };
+
+        // Both are kept at one tick or more, so that they can grow again
+        let scale =
+            |ticks: usize| ((ticks as f64 * factor).round() as usize).max(1);
+        if param == 0 {
+            let period = scale(period);
+            Some(Element::Clock {
+                period: period,
+                duty: duty.min(period),
+            })
+        } else {
+            let duty = scale(duty);
+            Some(Element::Clock {
+                period: period.max(duty),
+                duty: duty,
+            })
+        }
+    }
+
+    fn init_cell(&self, _element: &Element, _cell_index: usize) -> CellInit {
+        timed_init_cell()
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        if let Element::Clock { period, duty } = component.element {
+            let on = state.tick % period < duty;
+            set_on(component, state, on);
+        }
+
+        None
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+        let size = draw_box(ctx, camera, c)?;
+
+        // Clock face with a single hand
+        let center = graphics::Point::new(p_t.x, p_t.y);
+        let hand = graphics::Point::new(p_t.x, p_t.y - size / 3.0);
+        graphics::circle(
+            ctx,
+            graphics::DrawMode::Line,
+            center,
+            size / 2.0,
+            50,
+        )?;
+        graphics::line(ctx, &vec![center, hand])
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        kinds::fill_cell_circle(ctx, camera, c, cell_index)
+    }
+}
+
+impl ElementKind for Pattern {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        timed_descr()
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Pattern { ref bits } => {
+                Some(format!("Pattern {}", pattern_to_string(bits)))
+            }
+            _ => None,
+        }
+    }
+
+    fn init_cell(&self, _element: &Element, _cell_index: usize) -> CellInit {
+        timed_init_cell()
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        let on = match component.element {
+            Element::Pattern { ref bits } => bits[state.tick % bits.len()],
+            _ => return None,
+        };
+        set_on(component, state, on);
+
+        None
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let bits_str = match c.element {
+            Element::Pattern { ref bits } => pattern_to_string(bits),
+            _ => return Ok(()),
+        };
+
+        let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+        let size = draw_box(ctx, camera, c)?;
+
+        let text = graphics::Text::new(ctx, &bits_str, font)?;
+        let text_pos = graphics::Point::new(p_t.x, p_t.y + size);
+        text.draw(ctx, text_pos, 0.0)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        kinds::fill_cell_circle(ctx, camera, c, cell_index)
+    }
+}
+
+// Draw the box around a clock or pattern, and return its size on the screen
+#[cfg(feature = "gui")]
+fn draw_box(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+) -> GameResult<f32> {
+    let p_t = camera.transform(c.pos.cast() * EDGE_LENGTH);
+    let size = camera.transform_distance(EDGE_LENGTH * 0.5);
+    let r = graphics::Rect {
+        x: p_t.x,
+        y: p_t.y,
+        w: size,
+        h: size,
+    };
+    graphics::rectangle(ctx, graphics::DrawMode::Line, r)?;
+
+    Ok(size)
+}
@@ -0,0 +1,164 @@
+#[cfg(feature = "gui")]
+use cgmath::Vector2;
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+use flow;
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+// Like a valve, cell 0 and cell 1 are connected by an internal edge. The edge
+// is enabled while the toggle is on, which is flipped by clicking it during
+// the simulation. flow::Component::active is whether the toggle is on.
+pub struct Toggle;
+
+impl ElementKind for Toggle {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr {
+            size: circuit::Coords::new(0, 0),
+            cells: vec![(Dir::Left, 0), (Dir::Right, 0)],
+            cell_edges: vec![vec![Dir::Left], vec![Dir::Right]],
+        }
+    }
+
+    fn params(&self, element: &Element) -> Option<String> {
+        match element {
+            &Element::Toggle { on } => {
+                Some(format!("Initially {}", if on { "on" } else { "off" }))
+            }
+            _ => None,
+        }
+    }
+
+    fn internal_edges(&self, _element: &Element) -> Vec<(usize, usize)> {
+        vec![(0, 1)]
+    }
+
+    fn init_active(&self, element: &Element) -> bool {
+        match element {
+            &Element::Toggle { on } => on,
+            _ => false,
+        }
+    }
+
+    fn update(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> Option<bool> {
+        set_enabled(component, state);
+        None
+    }
+
+    fn click(&self, component: &mut flow::Component, state: &mut flow::State) {
+        component.active = !component.active;
+        set_enabled(component, state);
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let on = match c.element {
+            Element::Toggle { on } => on,
+            _ => return Ok(()),
+        };
+
+        draw_lever(ctx, camera, c, on)
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw_state(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        component: &flow::Component,
+    ) -> GameResult<()> {
+        // The lever has been drawn as it starts, so cover that up first
+        graphics::set_color(ctx, graphics::Color::new(0.0, 0.0, 0.0, 1.0))?;
+        draw_lever(ctx, camera, c, !component.active)?;
+
+        graphics::set_color(ctx, graphics::Color::new(1.0, 1.0, 1.0, 1.0))?;
+        draw_lever(ctx, camera, c, component.active)
+    }
+
+    // The cells are drawn at the ends of the lever
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        let dir = Dir::Left.rotate_cw_n(c.rotation_cw);
+        let dir = if cell_index == 0 { dir } else { dir.invert() };
+        let p: Vector2<f32> = c.pos.cast() + dir.delta().cast() / 4.0;
+        let p_t = camera.transform(p * EDGE_LENGTH);
+        let size = camera.transform_distance(EDGE_LENGTH * 0.2);
+
+        let r = graphics::Rect {
+            x: p_t.x,
+            y: p_t.y,
+            w: size,
+            h: size,
+        };
+        graphics::rectangle(ctx, graphics::DrawMode::Fill, r)
+    }
+}
+
+fn set_enabled(component: &flow::Component, state: &mut flow::State) {
+    let edge_idx = state
+        .graph
+        .edge_index((component.id, 0), (component.id, 1));
+    state.flow.edge_mut(edge_idx).enabled = component.active;
+}
+
+// A lever from the left to the right side of the toggle. When the toggle is
+// off, the lever is turned away from the right side.
+#[cfg(feature = "gui")]
+fn draw_lever(
+    ctx: &mut Context,
+    camera: &Camera,
+    c: &Component,
+    on: bool,
+) -> GameResult<()> {
+    let dir = Dir::Left.rotate_cw_n(c.rotation_cw);
+    let orth_dir = dir.rotate_cw();
+    let left: Vector2<f32> = c.pos.cast() + dir.delta().cast() / 4.0;
+    let right: Vector2<f32> = if on {
+        c.pos.cast() + dir.invert().delta().cast() / 4.0
+    } else {
+        c.pos.cast() + orth_dir.delta().cast() / 4.0
+    };
+
+    let points = vec![left, right]
+        .into_iter()
+        .map(|p| {
+            let p_t = camera.transform(p * EDGE_LENGTH);
+            graphics::Point { x: p_t.x, y: p_t.y }
+        })
+        .collect::<Vec<_>>();
+
+    graphics::line(ctx, &points)
+}
@@ -0,0 +1,131 @@
+#[cfg(feature = "gui")]
+use cgmath::Vector2;
+#[cfg(feature = "gui")]
+use ggez::{GameResult, Context};
+#[cfg(feature = "gui")]
+use ggez::graphics;
+
+use types::Dir;
+use circuit::{self, Element, ElementDescr};
+use flow;
+use kinds::ElementKind;
+#[cfg(feature = "gui")]
+use circuit::Component;
+#[cfg(feature = "gui")]
+use camera::Camera;
+#[cfg(feature = "gui")]
+use display::{Display, EDGE_LENGTH};
+
+// Pressure difference across a valve that is needed to open or close it.
+// This keeps the valves from flipping back and forth because of the
+// inaccuracy of the solver when the pressure on both sides is the same.
+const VALVE_TOLERANCE: f64 = 1e-6;
+
+// The inlet (cell 0) and the outlet (cell 1) are connected by an internal
+// edge, which is only enabled while the pressure at the inlet is higher.
+pub struct Valve;
+
+impl ElementKind for Valve {
+    fn descr(&self, _element: &Element) -> ElementDescr {
+        ElementDescr {
+            size: circuit::Coords::new(0, 0),
+            cells: vec![(Dir::Left, 0), (Dir::Right, 0)],
+            cell_edges: vec![vec![Dir::Left], vec![Dir::Right]],
+        }
+    }
+
+    fn internal_edges(&self, _element: &Element) -> Vec<(usize, usize)> {
+        vec![(0, 1)]
+    }
+
+    // Open the valve if it has a higher pressure at its inlet than at its
+    // outlet, and close it if it is the other way around
+    fn update_after_solve(
+        &self,
+        component: &mut flow::Component,
+        state: &mut flow::State,
+    ) -> bool {
+        let press_in = state.flow.node(component.cells[0]).pressure;
+        let press_out = state.flow.node(component.cells[1]).pressure;
+        let edge_idx = state
+            .graph
+            .edge_index((component.id, 0), (component.id, 1));
+        let edge = state.flow.edge_mut(edge_idx);
+
+        let enabled = if edge.enabled {
+            press_in - press_out >= -VALVE_TOLERANCE
+        } else {
+            press_in - press_out > VALVE_TOLERANCE
+        };
+        let changed = enabled != edge.enabled;
+        edge.enabled = enabled;
+
+        changed
+    }
+
+    #[cfg(feature = "gui")]
+    fn draw(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+    ) -> GameResult<()> {
+        let (triangle, bar) = valve_shape(camera, c);
+        graphics::polygon(ctx, graphics::DrawMode::Line, &triangle)?;
+        graphics::line(ctx, &bar)
+    }
+
+    // The triangle shows the pressure at the inlet, the bar the pressure at
+    // the outlet
+    #[cfg(feature = "gui")]
+    fn draw_cell(
+        &self,
+        _display: &Display,
+        ctx: &mut Context,
+        _font: &graphics::Font,
+        camera: &Camera,
+        c: &Component,
+        cell_index: usize,
+        _cell: &flow::Cell,
+    ) -> GameResult<()> {
+        let (triangle, bar) = valve_shape(camera, c);
+        if cell_index == 0 {
+            graphics::polygon(ctx, graphics::DrawMode::Fill, &triangle)
+        } else {
+            graphics::line(ctx, &bar)
+        }
+    }
+}
+
+// Triangle pointing from the inlet to the outlet of a valve, and the bar at
+// its tip, in screen coordinates
+#[cfg(feature = "gui")]
+fn valve_shape(
+    camera: &Camera,
+    c: &Component,
+) -> (Vec<graphics::Point>, Vec<graphics::Point>) {
+    let dir = Dir::Left.rotate_cw_n(c.rotation_cw);
+    let orth_dir = dir.rotate_cw();
+    let left: Vector2<f32> = c.pos.cast() + dir.delta().cast() / 4.0;
+    let right: Vector2<f32> = c.pos.cast() + dir.invert().delta().cast() / 4.0;
+
+    let points = vec![
+        left + orth_dir.delta().cast() / 4.0,
+        left + orth_dir.invert().delta().cast() / 4.0,
+        right,
+        right + orth_dir.delta().cast() / 4.0,
+        right + orth_dir.invert().delta().cast() / 4.0,
+    ];
+    let mut points = points
+        .into_iter()
+        .map(|p| {
+            let p_t = camera.transform(p * EDGE_LENGTH);
+            graphics::Point { x: p_t.x, y: p_t.y }
+        })
+        .collect::<Vec<_>>();
+
+    let bar = points.split_off(3);
+    (points, bar)
+}
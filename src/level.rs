@@ -1,13 +1,37 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::ops::Deref;
+use std::path::Path;
 
-use circuit::{self, Circuit, Element, Action};
+use circuit::{self, Circuit, Element, Action, ChipDb, Hierarchy,
+              UnfoldError};
 use flow;
+use format::{self, Reader, Line};
+use sequence_level::{SequenceDescr, SequenceLevel, SEQUENCE_IO_SIZE,
+                     DEFAULT_OUTPUT_THRESHOLD};
+
+// Simulated time of one tick in seconds. Ticks always have this length, no
+// matter how often they are run, so that the outcome does not depend on the
+// frame rate.
+pub const TICK_DURATION: f64 = 0.1;
 
 pub struct Level {
     pub input_size: usize,
     pub input_pos: circuit::Coords,
+    pub input_pressure: f64,
     pub output_size: usize,
     pub output_pos: circuit::Coords,
+
+    // See flow::State::inertia
+    pub inertia: f64,
+
+    pub mode: flow::SimulationMode,
+
+    // See flow::State::settle_iterations
+    pub settle_iterations: Option<usize>,
+
     pub create_impl: Box<Fn() -> Box<LevelImpl>>,
 }
 
@@ -17,6 +41,48 @@ pub enum Outcome {
     Failure,
 }
 
+// Reasons why a level can not be started with a circuit
+#[derive(Debug)]
+pub enum StartError {
+    Unfold(UnfoldError),
+
+    // The circuit does not have an input and an output with the number of
+    // cells that the level needs. The sizes are those of the circuit, where
+    // a missing input or output has size zero.
+    IoMismatch {
+        input_size: usize,
+        output_size: usize,
+    },
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &StartError::Unfold(ref err) => write!(f, "{}", err),
+            &StartError::IoMismatch {
+                input_size,
+                output_size,
+            } => write!(
+                f,
+                "the circuit has an input of size {} and an output of size {}, \
+                 but the level needs {} and {}",
+                input_size,
+                output_size,
+                SEQUENCE_IO_SIZE,
+                SEQUENCE_IO_SIZE
+            ),
+        }
+    }
+}
+
+impl error::Error for StartError {}
+
+impl From<UnfoldError> for StartError {
+    fn from(err: UnfoldError) -> StartError {
+        StartError::Unfold(err)
+    }
+}
+
 pub trait LevelImpl {
     fn time_step(&mut self, flow: &mut flow::State) -> Option<Outcome>;
 }
@@ -31,7 +97,10 @@ impl Level {
         let mut circuit = Circuit::new();
 
         {
-            let element = Element::Input { size: self.input_size };
+            let element = Element::Input {
+                size: self.input_size,
+                pressure: self.input_pressure,
+            };
             let component = element.new_component(self.input_pos, 0);
             let action = Action::PlaceComponent(component);
             action.perform(&mut circuit);
@@ -47,19 +116,270 @@ impl Level {
     }
 }
 
+// Levels are stored in the following format:
+//
+//     flow level <version>
+//     input <size> <x> <y>
+//     input_pressure <pressure>
+//     output <size> <x> <y>
+//     protocol sequence
+//     length <number of bits>
+//     order identity|reverse
+//     epochs <number of epochs>
+//     seed <seed>
+//     threshold <flow>
+//     inertia <seconds>
+//     mode instant|transport
+//     settle <max iterations>
+//
+// Levels share the version with circuits and chips, see format::VERSION, so
+// any version that the header accepts is fine. None of the changes in the
+// version history concern levels, so all versions are read the same way.
+//
+// The lines after the header can be given in any order. `epochs` defaults to
+// 1. Without `seed`, a new random sequence is used every time the level is
+// started. `threshold` is the flow above which an output cell counts as set,
+// and defaults to DEFAULT_OUTPUT_THRESHOLD. `inertia` defaults to 0, which
+// means that the flow follows the pressure within a single tick. `mode`
+// selects the flow::SimulationMode and defaults to `instant`. With `settle`,
+// switches react within the same tick, see flow::State::settle_iterations.
+// The protocol determines how the inputs are written and how the outputs are
+// checked, see SequenceDescr for the only protocol so far. `input_pressure`
+// is the pressure of the input cells of new circuits, and defaults to
+// circuit::DEFAULT_PRESSURE.
 impl Level {
-    pub fn new_state(&self, circuit: &Circuit) -> LevelState {
-        LevelState {
-            flow: flow::State::from_circuit(circuit),
-            level_impl: self.create_impl.deref()(),
+    pub fn load<R: BufRead>(read: R) -> format::Result<Level> {
+        let mut reader = Reader::new(read);
+        // The version does not matter for levels so far, see above
+        let _version = reader.header("level")?;
+
+        let mut input = None;
+        let mut output = None;
+        let mut input_pressure: f64 = circuit::DEFAULT_PRESSURE;
+        let mut protocol = None;
+        let mut length = None;
+        let mut reverse = None;
+        let mut epochs = 1;
+        let mut seed = None;
+        let mut threshold: f64 = DEFAULT_OUTPUT_THRESHOLD;
+        let mut inertia: f64 = 0.0;
+        let mut mode = flow::SimulationMode::Instant;
+        let mut settle_iterations = None;
+
+        while let Some(mut line) = reader.next_line()? {
+            let keyword = line.keyword().to_string();
+
+            match keyword.as_str() {
+                "input" => input = Some(read_io(&mut line)?),
+                "output" => output = Some(read_io(&mut line)?),
+                "input_pressure" => {
+                    input_pressure = line.next("pressure")?;
+                    if !(input_pressure >= 0.0 && input_pressure.is_finite()) {
+                        return Err(line.error(
+                            format!("invalid pressure: {}", input_pressure),
+                        ));
+                    }
+                }
+                "protocol" => {
+                    let name = line.next_str("protocol")?.to_string();
+                    if name != "sequence" {
+                        return Err(
+                            line.error(format!("unknown protocol: `{}`", name)),
+                        );
+                    }
+                    protocol = Some(name);
+                }
+                "length" => length = Some(line.next("sequence length")?),
+                "order" => {
+                    let order = line.next_str("order")?.to_string();
+                    reverse = Some(match order.as_str() {
+                        "identity" => false,
+                        "reverse" => true,
+                        _ => {
+                            return Err(line.error(
+                                format!("invalid order: `{}`", order),
+                            ))
+                        }
+                    });
+                }
+                "epochs" => epochs = line.next("number of epochs")?,
+                "seed" => seed = Some(line.next("seed")?),
+                "threshold" => {
+                    threshold = line.next("threshold")?;
+                    if !(threshold >= 0.0 && threshold.is_finite()) {
+                        return Err(line.error(
+                            format!("invalid threshold: {}", threshold),
+                        ));
+                    }
+                }
+                "mode" => {
+                    let name = line.next_str("simulation mode")?.to_string();
+                    mode = match name.as_str() {
+                        "instant" => flow::SimulationMode::Instant,
+                        "transport" => flow::SimulationMode::Transport,
+                        _ => {
+                            return Err(line.error(
+                                format!("invalid simulation mode: `{}`", name),
+                            ))
+                        }
+                    };
+                }
+                "settle" => {
+                    let iterations = line.next("settle iterations")?;
+                    if iterations < 2 {
+                        return Err(line.error(format!(
+                            "settle needs at least 2 iterations, not {}",
+                            iterations
+                        )));
+                    }
+                    settle_iterations = Some(iterations);
+                }
+                "inertia" => {
+                    inertia = line.next("inertia")?;
+                    if !(inertia >= 0.0 && inertia.is_finite()) {
+                        return Err(line.error(
+                            format!("invalid inertia: {}", inertia),
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(line.error(
+                        format!("unknown keyword: `{}`", keyword),
+                    ))
+                }
+            }
+
+            line.finish()?;
+        }
+
+        let missing =
+            |what: &str| format::Error::Invalid(format!("missing `{}`", what));
+        let (input_size, input_pos) = input.ok_or_else(|| missing("input"))?;
+        let (output_size, output_pos) =
+            output.ok_or_else(|| missing("output"))?;
+        protocol.ok_or_else(|| missing("protocol"))?;
+
+        let descr = SequenceDescr {
+            length: length.ok_or_else(|| missing("length"))?,
+            reverse: reverse.ok_or_else(|| missing("order"))?,
+            epochs: epochs,
+            seed: seed,
+            threshold: threshold,
+        };
+
+        if input_size != SEQUENCE_IO_SIZE || output_size != SEQUENCE_IO_SIZE {
+            return Err(format::Error::Invalid(format!(
+                "sequence levels need input and output of size {}",
+                SEQUENCE_IO_SIZE
+            )));
+        }
+        if descr.length == 0 || descr.epochs == 0 {
+            return Err(format::Error::Invalid(
+                format!("length and epochs must be positive"),
+            ));
         }
+
+        Ok(Level {
+            input_size,
+            input_pos,
+            input_pressure,
+            output_size,
+            output_pos,
+            inertia,
+            mode,
+            settle_iterations,
+            create_impl: Box::new(move || {
+                Box::new(SequenceLevel::new(&descr)) as Box<LevelImpl>
+            }),
+        })
+    }
+
+    pub fn load_file<P: AsRef<Path>>(path: P) -> format::Result<Level> {
+        Level::load(BufReader::new(File::open(path)?))
+    }
+
+    pub fn new_state(
+        &self,
+        circuit: &Circuit,
+    ) -> Result<LevelState, StartError> {
+        self.start(flow::State::from_circuit(circuit))
+    }
+
+    // Like new_state for the unfolded circuit, see flow::State::unfold
+    pub fn unfold_state(
+        &self,
+        circuit: &Circuit,
+        chip_db: &ChipDb,
+    ) -> Result<(LevelState, Hierarchy), StartError> {
+        let (flow, hierarchy) = flow::State::unfold(circuit, chip_db)?;
+
+        Ok((self.start(flow)?, hierarchy))
+    }
+
+    // SequenceLevel reads and writes the input and output cells by index, so
+    // they have to be there
+    fn start(&self, mut flow: flow::State) -> Result<LevelState, StartError> {
+        if flow.input_cells.len() != SEQUENCE_IO_SIZE ||
+            flow.output_cells.len() != SEQUENCE_IO_SIZE
+        {
+            return Err(StartError::IoMismatch {
+                input_size: flow.input_cells.len(),
+                output_size: flow.output_cells.len(),
+            });
+        }
+
+        flow.inertia = self.inertia;
+        flow.mode = self.mode;
+        flow.settle_iterations = self.settle_iterations;
+
+        Ok(LevelState {
+            flow: flow,
+            level_impl: self.create_impl.deref()(),
+        })
     }
 }
 
 impl LevelState {
     pub fn time_step(&mut self) -> Option<Outcome> {
         let outcome = self.level_impl.time_step(&mut self.flow);
-        flow::time_step(&mut self.flow, 0.0);
+        flow::time_step(&mut self.flow, TICK_DURATION);
         outcome
     }
 }
+
+fn read_io(line: &mut Line) -> format::Result<(usize, circuit::Coords)> {
+    let size = line.next("size")?;
+    let x = line.next("x coordinate")?;
+    let y = line.next("y coordinate")?;
+
+    Ok((size, circuit::Coords::new(x, y)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: &'static str = "flow level 1
+input 2 0 0
+output 2 20 0
+protocol sequence
+length 3
+order reverse
+seed 7
+";
+
+    #[test]
+    fn start_needs_io() {
+        let level = Level::load(LEVEL.as_bytes()).unwrap();
+
+        assert!(level.new_state(&level.new_circuit()).is_ok());
+
+        match level.new_state(&Circuit::new()) {
+            Err(StartError::IoMismatch {
+                input_size: 0,
+                output_size: 0,
+            }) => {}
+            _ => panic!("expected IoMismatch"),
+        }
+    }
+}
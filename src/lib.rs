@@ -0,0 +1,27 @@
+extern crate cgmath;
+extern crate rand;
+#[cfg(feature = "gui")]
+extern crate ggez;
+#[cfg(feature = "gui")]
+extern crate sdl2;
+
+pub mod types;
+pub mod canon_map;
+pub mod format;
+pub mod graph;
+pub mod circuit;
+pub mod flow;
+pub mod kinds;
+pub mod level;
+pub mod sequence_level;
+
+#[cfg(feature = "gui")]
+pub mod camera;
+#[cfg(feature = "gui")]
+pub mod camera_input;
+#[cfg(feature = "gui")]
+pub mod display;
+#[cfg(feature = "gui")]
+pub mod hud;
+#[cfg(feature = "gui")]
+pub mod input;
@@ -1,24 +1,11 @@
+extern crate flow;
 extern crate ggez;
-extern crate cgmath;
 extern crate floating_duration;
-extern crate rulinalg;
-extern crate rand;
 extern crate sdl2;
 
-mod types;
-mod display;
-mod circuit;
-mod hud;
-mod camera;
-mod camera_input;
-mod input;
-mod canon_map;
-mod flow;
-mod graph;
-mod level;
-mod test_level;
-//#[cfg(test)] mod tests;
-
+use std::env;
+use std::io;
+use std::process;
 use std::time::Duration;
 
 use floating_duration::TimeAsFloat;
@@ -27,23 +14,77 @@ use ggez::conf;
 use ggez::event::{self, MouseButton, MouseState, Mod};
 use ggez::{GameResult, Context};
 use ggez::graphics;
+use sdl2::keyboard;
+
+use flow::format;
+use flow::circuit::{ChipDb, ChipDescr, ChipId, Circuit, Coords, Element,
+                    Hierarchy, InstancePath};
+use flow::display::{Display, DrawMode};
+use flow::hud::{self, Hud};
+use flow::camera::Camera;
+use flow::camera_input::CameraInput;
+use flow::input::{Input, Keycode};
+use flow::level::{Level, LevelState, TICK_DURATION};
+use flow::flow::Diagnostic;
+
+// Directory of the chip library
+const CHIP_DIR: &'static str = "chips";
+
+// Upper bound for the number of ticks that are run in one frame, so that a
+// slow frame does not make the simulation fall further and further behind
+const MAX_TICKS_PER_FRAME: usize = 10;
+
+// Chips that are created when the library is empty
+fn default_chip_db() -> ChipDb {
+    let mut chip_db = ChipDb::new(CHIP_DIR);
+
+    for i in 2..11 {
+        let descr = ChipDescr {
+            inner_size: Coords::new(5, 5),
+            left_size: 1 + i % 3,
+            right_size: 1 + i % 3,
+        };
+        chip_db.add(format!("chip{}", i), descr).unwrap();
+    }
 
-use circuit::{ChipDb, Circuit};
-use display::{Display, DrawMode};
-use hud::Hud;
-use camera::Camera;
-use camera_input::CameraInput;
-use input::{Input, Keycode};
-use level::{Level, LevelState};
+    chip_db
+}
+
+// Saving works with either control key
+fn is_save_key(input: &Input) -> bool {
+    match input {
+        &Input::KeyDown {
+            keycode: Keycode::S,
+            keymod,
+            repeat: _,
+        } => {
+            keymod.contains(keyboard::LCTRLMOD) ||
+                keymod.contains(keyboard::RCTRLMOD)
+        }
+        _ => false,
+    }
+}
 
 struct MainState {
     chip_db: ChipDb,
 
     circuit: Circuit,
+    circuit_path: String,
 
     level: Level,
     level_state: Option<LevelState>,
 
+    // Chip instances of the circuit that is simulated, and the instance that
+    // is shown. Clicking a chip while simulating shows its instance.
+    hierarchy: Hierarchy,
+    instance_path: InstancePath,
+
+    // Whether ticks are run automatically while simulating
+    running: bool,
+
+    // Real time in seconds that has passed but not been simulated yet
+    unsimulated_time: f64,
+
     frames: usize,
 
     hud: Hud,
@@ -54,17 +95,47 @@ struct MainState {
 }
 
 impl MainState {
-    fn new(ctx: &mut Context) -> GameResult<MainState> {
+    fn new(
+        ctx: &mut Context,
+        circuit_path: String,
+        level: Level,
+    ) -> GameResult<MainState> {
         graphics::set_background_color(
             ctx,
             graphics::Color::new(0.0, 0.0, 0.0, 1.0),
         );
-        let level = test_level::test_level();
+        let circuit = match Circuit::load_file(&circuit_path) {
+            Ok(circuit) => circuit,
+            Err(format::Error::Io(ref err))
+                if err.kind() == io::ErrorKind::NotFound => level.new_circuit(),
+            Err(err) => {
+                println!("Can't load circuit from {}: {}", circuit_path, err);
+                level.new_circuit()
+            }
+        };
+        let chip_db = match ChipDb::load(CHIP_DIR) {
+            Ok(ref chip_db) if chip_db.ids().is_empty() => default_chip_db(),
+            Ok(chip_db) => chip_db,
+            Err(err) => {
+                // Don't continue with a default library, since saving would
+                // overwrite the existing chips
+                println!("Can't load chips from {}: {}", CHIP_DIR, err);
+                process::exit(1);
+            }
+        };
+        if let Err(err) = chip_db.check_circuit(&circuit) {
+            println!("Circuit refers to invalid chips: {}", err);
+        }
         let s = MainState {
-            chip_db: ChipDb::init(10),
-            circuit: level.new_circuit(),
+            chip_db: chip_db,
+            circuit: circuit,
+            circuit_path: circuit_path,
             level: level,
             level_state: None,
+            hierarchy: Hierarchy::new(),
+            instance_path: Vec::new(),
+            running: false,
+            unsimulated_time: 0.0,
             frames: 0,
             hud: Hud::new(ctx)?,
             display: Display::new(),
@@ -78,15 +149,43 @@ impl MainState {
         Ok(s)
     }
 
+    fn save(&mut self) {
+        match self.chip_db.save() {
+            Ok(()) => println!("Saved chips to {}", CHIP_DIR),
+            Err(err) => println!("Can't save chips to {}: {}", CHIP_DIR, err),
+        }
+        match self.circuit.save_file(&self.circuit_path) {
+            Ok(()) => println!("Saved circuit to {}", self.circuit_path),
+            Err(err) => println!(
+                "Can't save circuit to {}: {}",
+                self.circuit_path,
+                err
+            ),
+        }
+    }
+
     fn input_event(&mut self, input: &Input) {
         // Only allow changing the circuit when not simulating
         if self.level_state.is_none() {
+            // Keys that are typed into the hud have no other meaning
+            let typing = self.hud.is_typing();
+
             self.hud.input_event(
                 &mut self.circuit,
                 &mut self.chip_db,
                 &self.camera,
                 input,
             );
+
+            if typing {
+                return;
+            }
+        }
+
+        // Control-S saves, without also moving the camera down
+        if is_save_key(input) {
+            self.save();
+            return;
         }
 
         self.camera_input.input_event(&mut self.camera, input);
@@ -101,19 +200,67 @@ impl MainState {
                     &Some(_) => None,
                     &None => {
                         // Start simulation
-                        let unfolded_circuit =
-                            self.circuit.unfold(&self.chip_db);
-                        if let Some(circuit) = unfolded_circuit {
-                            Some(self.level.new_state(&circuit))
-                        } else {
-                            println!("Circuit is cyclic");
-                            None
+                        let level_state = self.level
+                            .unfold_state(&self.circuit, &self.chip_db);
+                        match level_state {
+                            Ok((level_state, hierarchy)) => {
+                                self.hierarchy = hierarchy;
+                                Some(level_state)
+                            }
+                            Err(err) => {
+                                println!("Can't start level: {}", err);
+                                None
+                            }
                         }
                     }
                 };
                 if self.level_state.is_some() {
                     self.hud.switch_chip(&None);
                 }
+                self.instance_path.clear();
+                self.running = false;
+                self.unsimulated_time = 0.0;
+            }
+            &Input::MouseButtonDown {
+                button: MouseButton::Left,
+                x,
+                y,
+            } => {
+                // While simulating, clicks go to the components, for example
+                // to flip toggles. Clicking a chip shows its instance.
+                if let Some(ref mut level_state) = self.level_state {
+                    let circuit =
+                        self.hud.cur_circuit(&self.circuit, &self.chip_db);
+                    let coords = hud::screen_to_grid_coords(&self.camera, x, y);
+                    let id = circuit.points().get(&coords).cloned();
+                    let element =
+                        id.map(|id| circuit.components()[&id].element.clone());
+
+                    match (id, element) {
+                        (Some(id), Some(Element::Chip(chip_id, _))) => {
+                            self.instance_path.push(id);
+                            self.hud.switch_chip(&Some(chip_id));
+                        }
+                        (Some(id), Some(_)) => {
+                            let flow_id = self.hierarchy
+                                .component_id(&self.instance_path, id);
+                            if let Some(flow_id) = flow_id {
+                                level_state.flow.click_component(flow_id);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            &Input::KeyDown {
+                keycode: Keycode::Backspace,
+                keymod: _,
+                repeat: _,
+            } if self.level_state.is_some() => {
+                // Go back to the instance that contains the shown one
+                self.instance_path.pop();
+                let chip_id = self.instance_chip_id();
+                self.hud.switch_chip(&chip_id);
             }
             &Input::KeyDown {
                 keycode,
@@ -121,25 +268,89 @@ impl MainState {
                 repeat: _,
             } => {
                 if keycode == Keycode::T {
-                    let finished = if let &mut Some(ref mut level_state) =
-                        &mut self.level_state
-                    {
-                        if let Some(outcome) = level_state.time_step() {
-                            println!("level outcome: {:?}", outcome);
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    };
+                    self.tick();
+                } else if keycode == Keycode::Return {
+                    self.running = !self.running;
+                    self.unsimulated_time = 0.0;
+                }
+            }
+            _ => {}
+        }
+    }
 
-                    if finished {
-                        self.level_state = None;
+    // Chip of the instance that is shown while simulating
+    fn instance_chip_id(&self) -> Option<ChipId> {
+        let mut chip_id = None;
+
+        for id in self.instance_path.iter() {
+            let circuit = match chip_id {
+                Some(ref chip_id) => self.chip_db.get_circuit(chip_id).unwrap(),
+                None => &self.circuit,
+            };
+            chip_id = match circuit.components()[id].element {
+                Element::Chip(ref chip_id, _) => Some(chip_id.clone()),
+                _ => None,
+            };
+        }
+
+        chip_id
+    }
+
+    // Run a single tick of the simulation, if there is one
+    fn tick(&mut self) {
+        let finished = if let &mut Some(ref mut level_state) =
+            &mut self.level_state
+        {
+            let outcome = level_state.time_step();
+
+            // Floating islands are expected whenever a switch cuts off a
+            // wire, so only report real problems
+            for diagnostic in level_state.flow.diagnostics.iter() {
+                match diagnostic {
+                    &Diagnostic::FloatingIsland(_) => {}
+                    _ => {
+                        println!("{}", diagnostic.with_origins(&self.hierarchy))
                     }
                 }
             }
-            _ => {}
+
+            if let Some(outcome) = outcome {
+                println!("level outcome: {:?}", outcome);
+                true
+            } else {
+                false
+            }
+        } else {
+            false
+        };
+
+        if finished {
+            self.level_state = None;
+            self.running = false;
+        }
+    }
+
+    // Run as many ticks as fit into the real time that has passed, so that
+    // the simulation runs at the same speed whatever the frame rate
+    fn run(&mut self, dt_s: f64) {
+        if !self.running || self.level_state.is_none() {
+            return;
+        }
+
+        self.unsimulated_time += dt_s;
+
+        let mut ticks = 0;
+        while self.unsimulated_time >= TICK_DURATION &&
+            self.level_state.is_some()
+        {
+            if ticks == MAX_TICKS_PER_FRAME {
+                self.unsimulated_time = 0.0;
+                break;
+            }
+
+            self.tick();
+            self.unsimulated_time -= TICK_DURATION;
+            ticks += 1;
         }
     }
 }
@@ -149,6 +360,7 @@ impl event::EventHandler for MainState {
         let dt_s = dt.as_fractional_secs() as f32;
 
         self.camera_input.update(&mut self.camera, dt_s);
+        self.run(dt.as_fractional_secs());
         self.hud.update(
             ctx,
             &mut self.circuit,
@@ -173,17 +385,17 @@ impl event::EventHandler for MainState {
         )?;
 
         if let &Some(ref level_state) = &self.level_state {
-            if self.hud.cur_chip_id().is_none() {
-                let flow = &level_state.flow;
-                self.display.draw_flow(
-                    ctx,
-                    &self.hud.font,
-                    &self.camera,
-                    &self.circuit,
-                    flow,
-                )?;
-                //self.display.draw_flow_debug(ctx, &self.hud.font, &self.camera, &self.circuit, flow)?;
-            }
+            let flow = &level_state.flow;
+            self.display.draw_flow(
+                ctx,
+                &self.hud.font,
+                &self.camera,
+                cur_circuit,
+                flow,
+                &self.hierarchy,
+                &self.instance_path,
+            )?;
+            //self.display.draw_flow_debug(ctx, &self.hud.font, &self.camera, &self.circuit, flow)?;
         } else {
             self.hud.draw(
                 ctx,
@@ -262,7 +474,20 @@ pub fn main() {
     let c = conf::Conf::new();
     let ctx = &mut Context::load_from_conf("flow", "leod", c).unwrap();
 
-    let state = &mut MainState::new(ctx).unwrap();
+    let circuit_path =
+        env::args().nth(1).unwrap_or_else(|| "circuit.flow".to_string());
+    let level_path = env::args().nth(2).unwrap_or_else(
+        || "levels/reverse.level".to_string(),
+    );
+    let level = match Level::load_file(&level_path) {
+        Ok(level) => level,
+        Err(err) => {
+            println!("Can't load level from {}: {}", level_path, err);
+            process::exit(1);
+        }
+    };
+
+    let state = &mut MainState::new(ctx, circuit_path, level).unwrap();
 
     if let Err(e) = event::run(ctx, state) {
         println!("Error encountered: {}", e);
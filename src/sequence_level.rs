@@ -0,0 +1,111 @@
+use rand::{self, Rng, SeedableRng, StdRng};
+
+use flow;
+use level::{Outcome, LevelImpl};
+
+// A level in which a sequence of bits is written to the input, and the same
+// sequence (or its reverse) has to be read from the output. Both the input
+// and the output consist of two cells: the first one signals that a bit is
+// being transmitted, the second one is the value of the bit.
+#[derive(Clone, Debug)]
+pub struct SequenceDescr {
+    // Number of bits in the sequence
+    pub length: usize,
+
+    // Whether the output has to be the reverse of the input
+    pub reverse: bool,
+
+    // Number of times the sequence has to be read successfully
+    pub epochs: usize,
+
+    // Seed for generating the sequence. If there is no seed, a new sequence
+    // is generated every time the level is started.
+    pub seed: Option<usize>,
+
+    // Flow into an output cell above which the cell counts as set
+    pub threshold: f64,
+}
+
+// Number of cells the input and output need for a sequence level
+pub const SEQUENCE_IO_SIZE: usize = 2;
+
+pub const DEFAULT_OUTPUT_THRESHOLD: f64 = 0.001;
+
+pub struct SequenceLevel {
+    seq: Vec<bool>,
+    max_epochs: usize,
+    reverse: bool,
+    threshold: f64,
+    written: usize,
+    read: usize,
+    epochs: usize,
+}
+
+impl SequenceLevel {
+    pub fn new(descr: &SequenceDescr) -> SequenceLevel {
+        let seq = match descr.seed {
+            Some(seed) => {
+                let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+                (0..descr.length).map(|_| rng.gen()).collect()
+            }
+            None => {
+                let mut rng = rand::thread_rng();
+                (0..descr.length).map(|_| rng.gen()).collect()
+            }
+        };
+
+        SequenceLevel {
+            seq,
+            max_epochs: descr.epochs,
+            reverse: descr.reverse,
+            threshold: descr.threshold,
+            written: 0,
+            read: 0,
+            epochs: 0,
+        }
+    }
+}
+
+impl LevelImpl for SequenceLevel {
+    fn time_step(&mut self, state: &mut flow::State) -> Option<Outcome> {
+        if self.written < self.seq.len() {
+            state.flow.node_mut(state.input_cells[0]).enabled = true;
+            state.flow.node_mut(state.input_cells[1]).enabled = self.seq
+                [self.written];
+            self.written += 1;
+        } else {
+            state.flow.node_mut(state.input_cells[0]).enabled = false;
+            state.flow.node_mut(state.input_cells[1]).enabled = false;
+        }
+
+        if state.flow.node(state.output_cells[0]).in_flow > self.threshold {
+            let output =
+                state.flow.node(state.output_cells[1]).in_flow > self.threshold;
+
+            let idx = if self.reverse {
+                self.seq.len() - (self.read + 1)
+            } else {
+                self.read
+            };
+
+            if output != self.seq[idx] {
+                Some(Outcome::Failure)
+            } else {
+                self.read += 1;
+                if self.read == self.seq.len() {
+                    self.read = 0;
+                    self.epochs += 1;
+                    if self.epochs == self.max_epochs {
+                        Some(Outcome::Success)
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    }
+}